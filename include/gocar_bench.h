@@ -0,0 +1,85 @@
+/*
+ * Timing API for benchmarks in benches directory.
+ *
+ * Each benchmark is a function taking an opaque pointer, which is run using
+ * gocar_bench("name", function, data) from main. Results are printed to
+ * standard output in a format understood by `gocar bench`.
+ */
+#ifndef GOCAR_BENCH_H
+#define GOCAR_BENCH_H
+
+/*
+ * clock_gettime() isn't declared in strict ISO C modes otherwise. This has no effect if a libc
+ * header was included before, in which case a clock available in ISO C is used below.
+ */
+#if !defined(_POSIX_C_SOURCE) || _POSIX_C_SOURCE < 199309L
+#undef _POSIX_C_SOURCE
+#define _POSIX_C_SOURCE 199309L
+#endif
+
+#include <stdio.h>
+#include <time.h>
+
+/* Number of measured samples of each benchmark */
+#define GOCAR_BENCH_SAMPLES 20
+/* Minimal duration of a single sample in nanoseconds */
+#define GOCAR_BENCH_SAMPLE_NS 10000000.0
+
+/* Prevents the compiler from optimizing away computation of the pointed-to value. */
+static inline void gocar_bench_black_box(void *value) {
+    __asm__ volatile("" : : "g"(value) : "memory");
+}
+
+static inline double gocar_bench_now_ns(void) {
+#if defined(CLOCK_MONOTONIC)
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    return (double)now.tv_sec * 1e9 + (double)now.tv_nsec;
+#elif defined(TIME_UTC)
+    /* C11 wall clock */
+    struct timespec now;
+    timespec_get(&now, TIME_UTC);
+    return (double)now.tv_sec * 1e9 + (double)now.tv_nsec;
+#else
+    /* Processor time, measuring only the benchmark's own thread as long as it has no others */
+    return (double)clock() * (1e9 / CLOCKS_PER_SEC);
+#endif
+}
+
+static inline double gocar_bench_sample(void (*func)(void *), void *data, unsigned long long iterations) {
+    unsigned long long i;
+    double start = gocar_bench_now_ns();
+    for (i = 0; i < iterations; ++i) {
+        func(data);
+    }
+    return gocar_bench_now_ns() - start;
+}
+
+/* Measures func and prints iterations per sample, mean and variance of duration of one iteration. */
+static inline void gocar_bench(const char *name, void (*func)(void *), void *data) {
+    unsigned long long iterations = 1;
+    double samples[GOCAR_BENCH_SAMPLES];
+    double mean = 0, variance = 0;
+    int i;
+
+    /* Warm up and find the number of iterations filling a sample */
+    while (gocar_bench_sample(func, data, iterations) < GOCAR_BENCH_SAMPLE_NS && iterations < (1ULL << 40)) {
+        iterations *= 2;
+    }
+
+    for (i = 0; i < GOCAR_BENCH_SAMPLES; ++i) {
+        samples[i] = gocar_bench_sample(func, data, iterations) / (double)iterations;
+        mean += samples[i];
+    }
+    mean /= GOCAR_BENCH_SAMPLES;
+
+    for (i = 0; i < GOCAR_BENCH_SAMPLES; ++i) {
+        variance += (samples[i] - mean) * (samples[i] - mean);
+    }
+    variance /= GOCAR_BENCH_SAMPLES - 1;
+
+    printf("gocar-bench %s %llu %f %f\n", name, iterations, mean, variance);
+    fflush(stdout);
+}
+
+#endif
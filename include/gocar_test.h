@@ -0,0 +1,89 @@
+/*
+ * Unit tests inside of library sources.
+ *
+ * Write tests using GOCAR_TEST(name) { ... } and GOCAR_ASSERT(condition).
+ * They are compiled only when building with -DGOCAR_TEST, which `gocar test` does.
+ * Otherwise test functions are unused inline functions, which compilers don't emit.
+ */
+#ifndef GOCAR_TEST_H
+#define GOCAR_TEST_H
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define GOCAR_ASSERT(cond) do { \
+        if (!(cond)) { \
+            fprintf(stderr, "%s:%d: assertion failed: %s\n", __FILE__, __LINE__, #cond); \
+            exit(1); \
+        } \
+    } while (0)
+
+#ifdef GOCAR_TEST
+
+/* The option only enables tests, GOCAR_TEST is redefined below. */
+#undef GOCAR_TEST
+#define GOCAR_TEST_ENABLED
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+struct gocar_test {
+    const char *name;
+    const char *file;
+    void (*func)(void);
+    struct gocar_test *next;
+};
+
+extern struct gocar_test *gocar_tests;
+
+#ifdef __cplusplus
+}
+#endif
+
+#define GOCAR_TEST(name) \
+    static void gocar_test_fn_##name(void); \
+    static struct gocar_test gocar_test_entry_##name = { #name, __FILE__, gocar_test_fn_##name, 0 }; \
+    __attribute__((constructor)) static void gocar_test_register_##name(void) { \
+        gocar_test_entry_##name.next = gocar_tests; \
+        gocar_tests = &gocar_test_entry_##name; \
+    } \
+    static void gocar_test_fn_##name(void)
+
+#ifdef GOCAR_TEST_MAIN
+struct gocar_test *gocar_tests = 0;
+
+int main(int argc, char **argv) {
+    struct gocar_test *test;
+
+    if (argc == 2 && strcmp(argv[1], "--list") == 0) {
+        for (test = gocar_tests; test; test = test->next) {
+            printf("%s\t%s\n", test->name, test->file);
+        }
+        return 0;
+    }
+
+    if (argc == 2) {
+        for (test = gocar_tests; test; test = test->next) {
+            if (strcmp(test->name, argv[1]) == 0) {
+                test->func();
+                return 0;
+            }
+        }
+        fprintf(stderr, "unknown test %s\n", argv[1]);
+        return 2;
+    }
+
+    fprintf(stderr, "usage: %s --list | TEST\n", argv[0]);
+    return 2;
+}
+#endif
+
+#else
+
+#define GOCAR_TEST(name) __attribute__((unused)) static inline void gocar_test_fn_##name(void)
+
+#endif
+
+#endif
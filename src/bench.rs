@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use super::{BuildEnv, Command, FsResult, GocarResult, ResultExt};
+
+/// File in the target directory storing results of the previous run
+const RESULTS_FILE: &str = "bench-results.txt";
+
+/// Result of a single benchmark measured by `gocar_bench()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// Name of the benchmark prefixed with the name of the program, e.g. `parse::small`
+    pub name: String,
+    /// Iterations in a single sample
+    pub iterations: u64,
+    /// Mean duration of one iteration in nanoseconds
+    pub mean: f64,
+    /// Standard deviation of duration of one iteration in nanoseconds
+    pub stddev: f64,
+}
+
+impl BenchResult {
+    /// Parses line `gocar-bench NAME ITERATIONS MEAN VARIANCE` printed by `gocar_bench()`
+    ///
+    /// The name may contain spaces, so the numbers are taken from the end.
+    fn parse(program: &str, line: &str) -> Option<Self> {
+        let mut parts = line.strip_prefix("gocar-bench ")?.rsplitn(4, ' ');
+        let variance = parts.next()?.parse::<f64>().ok()?;
+        let mean = parts.next()?.parse().ok()?;
+        let iterations = parts.next()?.parse().ok()?;
+        let name = parts.next().filter(|name| !name.is_empty())?;
+
+        Some(BenchResult {
+            name: format!("{}::{}", program, name),
+            iterations,
+            mean,
+            stddev: variance.max(0.0).sqrt(),
+        })
+    }
+
+    /// Relative change of the mean against `previous` in percent
+    ///
+    /// Returns `None` if the difference is within standard deviation of either run.
+    fn change(&self, previous: &BenchResult) -> Option<f64> {
+        let difference = self.mean - previous.mean;
+        if difference.abs() <= self.stddev.max(previous.stddev) || previous.mean == 0.0 {
+            None
+        } else {
+            Some(difference * 100.0 / previous.mean)
+        }
+    }
+}
+
+/// Some benchmark programs failed
+#[derive(Debug)]
+pub struct BenchesFailed(pub Vec<String>);
+
+impl fmt::Display for BenchesFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "benchmarks failed: {}", self.0.join(", "))
+    }
+}
+
+fn load_results(path: &Path) -> FsResult<BTreeMap<String, BenchResult>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(err) => return Err(err).err_ctx(|| (path.to_owned(), "read file")),
+    };
+
+    // Unparseable lines are skipped, losing the comparison isn't worth failing the run.
+    let results = content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.rsplitn(4, '\t');
+            let stddev = parts.next()?.parse().ok()?;
+            let mean = parts.next()?.parse().ok()?;
+            let iterations = parts.next()?.parse().ok()?;
+            let name = parts.next()?.to_owned();
+            Some((name.clone(), BenchResult { name, iterations, mean, stddev, }))
+        })
+        .collect();
+
+    Ok(results)
+}
+
+fn save_results(path: &Path, results: &BTreeMap<String, BenchResult>) -> FsResult<()> {
+    let content = results
+        .values()
+        .map(|result| format!("{}\t{}\t{}\t{}\n", result.name, result.iterations, result.mean, result.stddev))
+        .collect::<String>();
+
+    std::fs::write(path, content).err_ctx(|| (path.to_owned(), "write file"))
+}
+
+fn print_result(result: &BenchResult, previous: Option<&BenchResult>) {
+    let change = match previous {
+        Some(previous) => match result.change(previous) {
+            Some(change) if change < 0.0 => format!(", \u{1B}[32mimproved\u{1B}[0m by {:.2}%", -change),
+            Some(change) => format!(", \u{1B}[31mregressed\u{1B}[0m by {:.2}%", change),
+            None => format!(", no change (previously {:.2} ns/iter)", previous.mean),
+        },
+        None => String::new(),
+    };
+
+    println!("bench {} ... {:.2} ns/iter (+/- {:.2}), {} iterations{}", result.name, result.mean, result.stddev, result.iterations, change);
+}
+
+/// Runs built benchmark programs one by one and compares results with the previous run.
+///
+/// Benchmarks aren't run in parallel so that they don't affect each other. Output which isn't
+/// a result is passed through. A failing program doesn't stop the others, results measured
+/// before it failed are kept and the failure is reported after saving them.
+pub fn run_benches(benches: Vec<(String, PathBuf)>, env: &BuildEnv) -> GocarResult<Vec<BenchResult>> {
+    let results_path = env.target_dir.join(RESULTS_FILE);
+    let mut previous_results = load_results(&results_path)?;
+    let mut results = Vec::new();
+    let mut failed = Vec::new();
+
+    for (program, path) in benches {
+        println!("     \u{1B}[32;1mRunning\u{1B}[0m {:?}", path.strip_prefix(env.strip_prefix).unwrap_or(&path));
+        let (output, status) = Command::new(&path).output_with_status()?;
+        if !status.success() {
+            println!("      \u{1B}[31;1mError\u{1B}[0m: {}", status);
+            failed.push(program.clone());
+        }
+
+        for line in String::from_utf8_lossy(&output).lines() {
+            match BenchResult::parse(&program, line) {
+                Some(result) => {
+                    print_result(&result, previous_results.get(&result.name));
+                    results.push(result);
+                },
+                None => println!("{}", line),
+            }
+        }
+    }
+
+    // Results of benchmarks which didn't run this time are kept for future comparison
+    previous_results.extend(results.iter().map(|result| (result.name.clone(), result.clone())));
+    save_results(&results_path, &previous_results)?;
+
+    if failed.is_empty() {
+        Ok(results)
+    } else {
+        Err(BenchesFailed(failed).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BenchResult;
+
+    fn result(mean: f64, stddev: f64) -> BenchResult {
+        BenchResult { name: "a::b".to_owned(), iterations: 1, mean, stddev, }
+    }
+
+    #[test]
+    fn parse() {
+        let result = BenchResult::parse("parse", "gocar-bench small 1024 12.500000 4.000000").unwrap();
+        assert_eq!(result.name, "parse::small");
+        assert_eq!(result.iterations, 1024);
+        assert_eq!(result.mean, 12.5);
+        assert_eq!(result.stddev, 2.0);
+        assert_eq!(BenchResult::parse("parse", "gocar-bench with spaces 8 1.5 0").unwrap().name, "parse::with spaces");
+        assert_eq!(BenchResult::parse("parse", "hello"), None);
+        assert_eq!(BenchResult::parse("parse", "gocar-bench 1024 12.5 4"), None);
+        assert_eq!(BenchResult::parse("parse", "gocar-bench small 1024 x 1"), None);
+    }
+
+    #[test]
+    fn change() {
+        assert_eq!(result(110.0, 1.0).change(&result(100.0, 1.0)), Some(10.0));
+        assert_eq!(result(90.0, 1.0).change(&result(100.0, 1.0)), Some(-10.0));
+        assert_eq!(result(105.0, 10.0).change(&result(100.0, 1.0)), None);
+    }
+}
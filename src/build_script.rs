@@ -0,0 +1,115 @@
+use std::path::{Path, PathBuf};
+use super::{Command, GocarResult, Languages, Profile, Project, ResultExt, canonicalize, canonicalize_custom_wd, get_file_mtime, header_to_unit, is_header, is_older};
+
+/// Directives printed by a build script
+///
+/// Build scripts communicate with gocar by printing lines like `gocar:include-dir=PATH` on
+/// their standard output. Other lines are passed through.
+#[derive(Debug, Default, PartialEq)]
+pub struct BuildScriptOutput {
+    /// Additional include directories, relative to the project directory
+    pub include_dirs: Vec<PathBuf>,
+    /// Libraries linked to every binary and shared library
+    pub link_libs: Vec<String>,
+    /// Files which cause the script to rerun when changed
+    pub rerun_if_changed: Vec<PathBuf>,
+}
+
+impl BuildScriptOutput {
+    fn parse(output: &str, print_other: bool) -> Self {
+        let mut result = BuildScriptOutput::default();
+        for line in output.lines() {
+            if let Some(dir) = line.strip_prefix("gocar:include-dir=") {
+                result.include_dirs.push(dir.into());
+            } else if let Some(lib) = line.strip_prefix("gocar:link-lib=") {
+                result.link_libs.push(lib.to_owned());
+            } else if let Some(file) = line.strip_prefix("gocar:rerun-if-changed=") {
+                result.rerun_if_changed.push(file.into());
+            } else if print_other {
+                println!("{}", line);
+            }
+        }
+        result
+    }
+}
+
+/// Runs the build script unless its previous output is still valid.
+///
+/// The script runs in the project directory with `GOCAR_OUT_DIR` set to `gen_dir`, where it's
+/// supposed to write generated files. Its output is stored in `output_file` and the script only
+/// reruns if the script itself or one of the files declared using `gocar:rerun-if-changed`
+/// is newer than the stored output.
+pub fn run(script: &Path, gen_dir: &Path, output_file: &Path, project_dir: &Path, profile_name: &str, profile: &Profile, languages: &Languages) -> GocarResult<BuildScriptOutput> {
+    let script = canonicalize_custom_wd(script, project_dir)?;
+
+    if let Some(mtime) = get_file_mtime(output_file)? {
+        let previous = std::fs::read(output_file).err_ctx(|| (output_file.to_owned(), "read file"))?;
+        let previous = BuildScriptOutput::parse(&String::from_utf8_lossy(&previous), false);
+        let inputs = std::iter::once(script.clone()).chain(previous.rerun_if_changed.iter().map(|file| project_dir.join(file)));
+        if !is_older(mtime, inputs)? {
+            return Ok(previous);
+        }
+    }
+
+    println!("     \u{1B}[32;1mRunning\u{1B}[0m build script {:?}", script.strip_prefix(project_dir).unwrap_or(&script));
+    let output = Command::new(&script)
+        .current_dir(project_dir)
+        .env("GOCAR_OUT_DIR", gen_dir)
+        .env("GOCAR_PROJECT_DIR", project_dir)
+        .env("GOCAR_PROFILE", profile_name)
+        .env("CC", profile.compiler(languages.get("c")?).as_os_str())
+        .env("CXX", profile.compiler(languages.get("cpp")?).as_os_str())
+        .output()?;
+
+    std::fs::write(output_file, &output).err_ctx(|| (output_file.to_owned(), "write file"))?;
+    Ok(BuildScriptOutput::parse(&String::from_utf8_lossy(&output), true))
+}
+
+/// Runs the post-build script in the project directory with `GOCAR_TARGET_DIR` set.
+pub fn run_post_build(script: &Path, target_dir: &Path, project_dir: &Path, profile_name: &str) -> GocarResult<()> {
+    let script = canonicalize_custom_wd(script, project_dir)?;
+
+    println!("     \u{1B}[32;1mRunning\u{1B}[0m post-build script {:?}", script.strip_prefix(project_dir).unwrap_or(&script));
+    Command::new(&script)
+        .current_dir(project_dir)
+        .env("GOCAR_TARGET_DIR", canonicalize(target_dir)?)
+        .env("GOCAR_PROJECT_DIR", project_dir)
+        .env("GOCAR_PROFILE", profile_name)
+        .spawn()?
+        .wait()?
+        .failure_into_error()
+        .map_err(Into::into)
+}
+
+/// Returns generated headers which don't have a corresponding generated source.
+pub fn generated_headers_only(gen_dir: &Path, project: &Project) -> GocarResult<Vec<PathBuf>> {
+    let mut headers = Vec::new();
+    find_headers_only(gen_dir, project, &mut headers)?;
+    Ok(headers)
+}
+
+fn find_headers_only(dir: &Path, project: &Project, headers: &mut Vec<PathBuf>) -> GocarResult<()> {
+    for entry in std::fs::read_dir(dir).err_ctx(|| (dir.to_owned(), "read directory"))? {
+        let path = entry.err_ctx(|| (dir.to_owned(), "read directory"))?.path();
+        if path.is_dir() {
+            find_headers_only(&path, project, headers)?;
+        } else if is_header(&path, &project.header_extensions) && header_to_unit(&path, &project.languages, std::iter::empty())?.is_none() {
+            headers.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BuildScriptOutput;
+
+    #[test]
+    fn directives() {
+        let output = BuildScriptOutput::parse("generating\ngocar:include-dir=proto\ngocar:link-lib=protobuf\ngocar:rerun-if-changed=proto/msg.proto\n", false);
+        assert_eq!(output.include_dirs, [std::path::Path::new("proto")]);
+        assert_eq!(output.link_libs, ["protobuf"]);
+        assert_eq!(output.rerun_if_changed, [std::path::Path::new("proto/msg.proto")]);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sha2::{Digest, Sha256};
+use super::{Command, FsResult, GocarResult, ResultExt, copy_file, create_dir_all};
+
+/// Local content-addressed cache of compiled objects
+///
+/// Objects are keyed by the unit, its preprocessed form, all compile options and the identity
+/// of the compiler. Include directories and line markers of the preprocessed unit are absolute
+/// paths, so objects are shared by builds of the same project in the same directory, e.g. after
+/// cleaning it or switching branches, but not by different checkouts.
+pub struct CompileCache {
+    dir: PathBuf,
+    compiler_ids: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CompileCache {
+    /// Opens the cache in `$GOCAR_CACHE_DIR`, `$XDG_CACHE_HOME/gocar` or `~/.cache/gocar`.
+    ///
+    /// Returns `None` if none of the variables is set.
+    pub fn open() -> Option<Self> {
+        let dir = std::env::var_os("GOCAR_CACHE_DIR").map(PathBuf::from)
+            .or_else(|| std::env::var_os("XDG_CACHE_HOME").map(|dir| Path::new(&dir).join("gocar")))
+            .or_else(|| std::env::var_os("HOME").map(|dir| Path::new(&dir).join(".cache").join("gocar")))?;
+
+        Some(Self::open_in(dir))
+    }
+
+    /// Opens the cache in `dir`.
+    pub fn open_in<P: Into<PathBuf>>(dir: P) -> Self {
+        CompileCache {
+            dir: dir.into(),
+            compiler_ids: Default::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Computes the key of the unit of `language` compiled using `compiler` with `options`.
+    ///
+    /// The key is the SHA-256 of all inputs. The source of the unit is hashed in addition to its
+    /// preprocessed form, because units which aren't preprocessed, like assembly, preprocess
+    /// to nothing.
+    pub fn key(&self, compiler: &Path, options: &[OsString], language: &str, unit: &Path) -> GocarResult<String> {
+        let source = std::fs::read(unit).err_ctx(|| (unit.to_owned(), "read file"))?;
+        let preprocessed = Command::new(compiler)
+            .args(options)
+            .arg("-E")
+            .arg(unit)
+            .output()?;
+        let compiler_id = self.compiler_id(compiler)?;
+
+        let mut hasher = Sha256::new();
+        // Every field is prefixed with its length, so that moving bytes between fields changes the key
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        field(compiler.to_string_lossy().as_bytes());
+        field(&compiler_id);
+        field(language.as_bytes());
+        field(&(options.len() as u64).to_le_bytes());
+        for option in options {
+            field(option.to_string_lossy().as_bytes());
+        }
+        field(&source);
+        field(&preprocessed);
+
+        Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Output of `compiler --version`, computed once per compiler
+    fn compiler_id(&self, compiler: &Path) -> GocarResult<Vec<u8>> {
+        if let Some(id) = self.compiler_ids.lock().unwrap().get(compiler) {
+            return Ok(id.clone());
+        }
+
+        let id = Command::new(compiler).arg("--version").output()?;
+        self.compiler_ids.lock().unwrap().insert(compiler.to_owned(), id.clone());
+        Ok(id)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(&key[2..]).with_extension("o")
+    }
+
+    /// Copies the cached object to `output` if it exists, counting hits and misses.
+    pub fn restore(&self, key: &str, output: &Path) -> FsResult<bool> {
+        let cached = self.path(key);
+        if !cached.exists() {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+
+        copy_file(cached, output)?;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Stores the compiled object.
+    ///
+    /// The object is copied to a temporary file first, so concurrent builds never see
+    /// a partially written object.
+    pub fn store(&self, key: &str, object: &Path) -> FsResult<()> {
+        let cached = self.path(key);
+        create_dir_all(cached.parent().unwrap())?;
+        let temporary = cached.with_extension(format!("tmp{}", std::process::id()));
+        copy_file(object, &temporary)?;
+        std::fs::rename(&temporary, &cached).err_ctx(|| (temporary, "rename file"))
+    }
+
+    /// Prints hit and miss statistics if the cache was used.
+    pub fn print_stats(&self) {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        if hits + misses > 0 {
+            println!("       \u{1B}[32;1mCache\u{1B}[0m {} hits, {} misses ({})", hits, misses, self.dir.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompileCache;
+
+    #[test]
+    fn layout() {
+        let cache = CompileCache::open_in("/cache");
+        assert_eq!(cache.path("0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"), std::path::Path::new("/cache/01/23456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef.o"));
+    }
+}
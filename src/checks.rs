@@ -0,0 +1,287 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use super::{Command, CompilerInfo, GocarResult, ResultExt, create_dir_all, prefixed_option, write_if_changed};
+
+/// Configure-time check of the platform, configured in `[checks]` by the name of the macro it
+/// defines in `config.h`
+///
+/// Exactly one of `header`, `function`, `sizeof` and `compiles` must be set.
+#[derive(Debug, Deserialize)]
+pub struct Check {
+    /// Header which must exist, defines the macro as 1
+    #[serde(default)]
+    pub header: Option<String>,
+    /// Function which must link, defines the macro as 1
+    #[serde(default)]
+    pub function: Option<String>,
+    /// Type whose size the macro is defined to
+    #[serde(default)]
+    pub sizeof: Option<String>,
+    /// Code which must compile, defines the macro as 1
+    #[serde(default)]
+    pub compiles: Option<String>,
+    /// Headers included before the checked code
+    #[serde(default)]
+    pub includes: Vec<String>,
+    /// Libraries linked when checking a function
+    #[serde(default)]
+    pub libs: Vec<String>,
+}
+
+enum Kind<'a> {
+    Header(&'a str),
+    Function(&'a str),
+    Sizeof(&'a str),
+    Compiles(&'a str),
+}
+
+impl Check {
+    fn kind(&self, name: &str) -> Result<Kind<'_>, InvalidCheck> {
+        match (&self.header, &self.function, &self.sizeof, &self.compiles) {
+            (Some(header), None, None, None) => Ok(Kind::Header(header)),
+            (None, Some(function), None, None) => Ok(Kind::Function(function)),
+            (None, None, Some(type_name), None) => Ok(Kind::Sizeof(type_name)),
+            (None, None, None, Some(code)) => Ok(Kind::Compiles(code)),
+            _ => Err(InvalidCheck(name.to_owned())),
+        }
+    }
+
+    fn prelude(&self) -> String {
+        self.includes.iter().map(|header| format!("#include <{}>\n", header)).collect()
+    }
+}
+
+/// The check doesn't have exactly one kind
+#[derive(Debug)]
+pub struct InvalidCheck(pub String);
+
+impl fmt::Display for InvalidCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "check {} must have exactly one of header, function, sizeof or compiles", self.0)
+    }
+}
+
+/// Compiles checks in `dir`, where their sources are kept for troubleshooting
+struct Runner<'a> {
+    compiler: &'a Path,
+    options: &'a [OsString],
+    lib_dirs: &'a [OsString],
+    dir: PathBuf,
+}
+
+impl Runner<'_> {
+    /// Compiles the code, also linking it if `libs` are given, and returns whether it succeeded
+    fn try_code(&self, name: &str, code: &str, libs: Option<&[String]>) -> GocarResult<bool> {
+        let source = self.dir.join(name).with_extension("c");
+        std::fs::write(&source, code).err_ctx(|| (source.clone(), "write file"))?;
+
+        let command = Command::new(self.compiler)
+            .args(self.options)
+            .null_stderr();
+        let command = match libs {
+            Some(libs) => command
+                .arg("-o")
+                .arg(self.dir.join(name))
+                .arg(&source)
+                .args(self.lib_dirs)
+                .args(libs.iter().map(|lib| prefixed_option("-l", lib))),
+            None => command
+                .arg("-c")
+                .arg("-o")
+                .arg(self.dir.join(name).with_extension("o"))
+                .arg(&source),
+        };
+
+        Ok(command.spawn()?.wait()?.success())
+    }
+
+    fn run(&self, name: &str, check: &Check) -> GocarResult<Option<String>> {
+        let prelude = check.prelude();
+        let found = |found: bool| if found { Some("1".to_owned()) } else { None };
+        match check.kind(name)? {
+            Kind::Header(header) => Ok(found(self.try_code(name, &format!("{}#include <{}>\n", prelude, header), None)?)),
+            Kind::Function(function) => {
+                // Without includes, the function is declared with a dummy prototype, like autoconf does
+                let declaration = if check.includes.is_empty() { format!("char {}(void);\n", function) } else { String::new() };
+                let code = format!("{}{}\nint main(void) {{\n    void *volatile pointer = (void *)&{};\n    return pointer == 0;\n}}\n", prelude, declaration, function);
+                Ok(found(self.try_code(name, &code, Some(&check.libs))?))
+            },
+            Kind::Sizeof(type_name) => self.size_of(name, &prelude, type_name),
+            Kind::Compiles(code) => Ok(found(self.try_code(name, &format!("{}{}\n", prelude, code), None)?)),
+        }
+    }
+
+    /// Finds the size by compiling assertions only, so it works when cross-compiling too
+    fn size_of(&self, name: &str, prelude: &str, type_name: &str) -> GocarResult<Option<String>> {
+        let holds = |condition: String| self.try_code(name, &format!("{}typedef char gocar_check[({}) ? 1 : -1];\n", prelude, condition), None);
+        if !holds(format!("sizeof({}) > 0", type_name))? {
+            return Ok(None);
+        }
+
+        let mut high = 1u64;
+        while !holds(format!("sizeof({}) <= {}", type_name, high))? {
+            // The condition can't hold if the compiler evaluates it wrongly
+            if high >= 1 << 32 {
+                return Ok(None);
+            }
+            high *= 2;
+        }
+
+        let mut low = high / 2 + 1;
+        while low < high {
+            let middle = (low + high) / 2;
+            if holds(format!("sizeof({}) <= {}", type_name, middle))? {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+
+        Ok(Some(high.to_string()))
+    }
+}
+
+/// Runs the checks which aren't cached yet and returns the content of `config.h`.
+///
+/// Results are cached in `config-cache` of `target_dir`, keyed by the check, the compiler and
+/// the options, so checks only rerun when any of them changes.
+pub fn configure(checks: &BTreeMap<String, Check>, compiler: &Path, compiler_info: &CompilerInfo, options: &[OsString], lib_dirs: &[OsString], target_dir: &Path) -> GocarResult<String> {
+    let cache_file = target_dir.join("config-cache");
+    let cached = std::fs::read_to_string(&cache_file).unwrap_or_default();
+    let cached = cached.lines().filter_map(parse_cache_line).collect::<HashMap<_, _>>();
+    let runner = Runner {
+        compiler,
+        options,
+        lib_dirs,
+        dir: target_dir.join("checks"),
+    };
+
+    let mut results = BTreeMap::new();
+    let mut cache = String::new();
+    for (name, check) in checks {
+        let key = cache_key(check, compiler, compiler_info, options, lib_dirs);
+
+        let result = match cached.get(&**name) {
+            Some((cached_key, result)) if *cached_key == key => result.clone(),
+            _ => {
+                create_dir_all(&runner.dir)?;
+                let result = runner.run(name, check)?;
+                let shown = match (&result, check.sizeof.is_some()) {
+                    (Some(size), true) => size,
+                    (Some(_), false) => "yes",
+                    (None, _) => "no",
+                };
+                println!("    \u{1B}[32;1mChecking\u{1B}[0m {}: {}", name, shown);
+                result
+            },
+        };
+
+        cache.push_str(&format!("{} {} {}\n", name, key, result.as_deref().unwrap_or("-")));
+        results.insert(&**name, result);
+    }
+
+    write_if_changed(&cache_file, cache.as_bytes())?;
+    Ok(config_header(&results))
+}
+
+/// SHA-256 of everything affecting the result of the check
+///
+/// Every field is prefixed with its length, so that moving bytes between fields changes the key.
+fn cache_key(check: &Check, compiler: &Path, compiler_info: &CompilerInfo, options: &[OsString], lib_dirs: &[OsString]) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    field(format!("{:?}", check).as_bytes());
+    field(compiler.to_string_lossy().as_bytes());
+    field(format!("{:?}", compiler_info).as_bytes());
+    for args in [options, lib_dirs] {
+        field(&(args.len() as u64).to_le_bytes());
+        for arg in args {
+            field(arg.to_string_lossy().as_bytes());
+        }
+    }
+
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses `NAME KEY RESULT` line of the cache, where `-` means the check failed
+fn parse_cache_line(line: &str) -> Option<(&str, (&str, Option<String>))> {
+    let mut parts = line.splitn(3, ' ');
+    let name = parts.next()?;
+    let key = parts.next()?;
+    let result = match parts.next()? {
+        "-" => None,
+        result => Some(result.to_owned()),
+    };
+
+    Some((name, (key, result)))
+}
+
+fn config_header(results: &BTreeMap<&str, Option<String>>) -> String {
+    let mut header = String::from("/* Generated by gocar from [checks] of Gocar.toml */\n#ifndef GOCAR_CONFIG_H\n#define GOCAR_CONFIG_H\n\n");
+    for (name, result) in results {
+        match result {
+            Some(value) => header.push_str(&format!("#define {} {}\n", name, value)),
+            None => header.push_str(&format!("/* #undef {} */\n", name)),
+        }
+    }
+    header.push_str("\n#endif\n");
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use crate::{CompilerFamily, CompilerInfo};
+    use super::{Check, cache_key, config_header, parse_cache_line};
+
+    #[test]
+    fn kinds() {
+        let checks = toml::from_str::<BTreeMap<String, Check>>(r#"
+            HAVE_UNISTD_H = { header = "unistd.h" }
+            HAVE_BOTH = { header = "stdio.h", function = "printf" }
+            HAVE_NONE = { includes = ["stdio.h"] }
+        "#).unwrap();
+
+        assert!(checks["HAVE_UNISTD_H"].kind("HAVE_UNISTD_H").is_ok());
+        assert_eq!(checks["HAVE_BOTH"].kind("HAVE_BOTH").err().unwrap().to_string(), "check HAVE_BOTH must have exactly one of header, function, sizeof or compiles");
+        assert!(checks["HAVE_NONE"].kind("HAVE_NONE").is_err());
+        assert_eq!(checks["HAVE_NONE"].prelude(), "#include <stdio.h>\n");
+    }
+
+    #[test]
+    fn header() {
+        let mut results = BTreeMap::new();
+        results.insert("SIZEOF_LONG", Some("8".to_owned()));
+        results.insert("HAVE_FOO_H", None);
+        assert_eq!(config_header(&results), "/* Generated by gocar from [checks] of Gocar.toml */\n#ifndef GOCAR_CONFIG_H\n#define GOCAR_CONFIG_H\n\n/* #undef HAVE_FOO_H */\n#define SIZEOF_LONG 8\n\n#endif\n");
+
+        assert_eq!(parse_cache_line("SIZEOF_LONG 0123456789abcdef 8"), Some(("SIZEOF_LONG", ("0123456789abcdef", Some("8".to_owned())))));
+        assert_eq!(parse_cache_line("HAVE_FOO_H 0123456789abcdef -"), Some(("HAVE_FOO_H", ("0123456789abcdef", None))));
+    }
+
+    #[test]
+    fn key() {
+        let checks = toml::from_str::<BTreeMap<String, Check>>(r#"
+            HAVE_UNISTD_H = { header = "unistd.h" }
+            HAVE_STDIO_H = { header = "stdio.h" }
+        "#).unwrap();
+        let info = CompilerInfo { family: CompilerFamily::Gcc, version: "12.2.0".parse().unwrap(), };
+        let key = |name: &str, options: &[&str], lib_dirs: &[&str]| {
+            let options = options.iter().map(Into::into).collect::<Vec<_>>();
+            let lib_dirs = lib_dirs.iter().map(Into::into).collect::<Vec<_>>();
+            cache_key(&checks[name], Path::new("cc"), &info, &options, &lib_dirs)
+        };
+
+        assert_eq!(key("HAVE_UNISTD_H", &["-O2"], &[]).len(), 64);
+        assert_eq!(key("HAVE_UNISTD_H", &["-O2"], &[]), key("HAVE_UNISTD_H", &["-O2"], &[]));
+        assert_ne!(key("HAVE_UNISTD_H", &["-O2"], &[]), key("HAVE_STDIO_H", &["-O2"], &[]));
+        assert_ne!(key("HAVE_UNISTD_H", &["-O2"], &[]), key("HAVE_UNISTD_H", &[], &["-O2"]));
+    }
+}
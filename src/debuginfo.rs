@@ -0,0 +1,151 @@
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use super::{BuildEnv, Command, GocarResult, ResultExt, create_dir_all};
+
+/// What is removed from linked binaries and shared libraries
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strip {
+    #[default]
+    None,
+    Debuginfo,
+    Symbols,
+}
+
+/// Placement of split debug information
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DebuginfoLayout {
+    /// `<artifact>.debug` next to the artifact
+    #[default]
+    Adjacent,
+    /// `.build-id/xx/yyyy.debug` in the target directory, where debuggers look it up
+    BuildId,
+}
+
+/// Option making the linker emit the build ID the debug information is looked up by
+pub fn link_option(env: &BuildEnv) -> Option<&'static str> {
+    if env.profile.split_debuginfo == Some(true) && env.profile.debuginfo_layout == Some(DebuginfoLayout::BuildId) {
+        Some("-Wl,--build-id")
+    } else {
+        None
+    }
+}
+
+/// Strips the freshly linked artifact and splits its debug information as configured by the
+/// profile.
+///
+/// Split debug information is removed from the artifact even if the profile doesn't strip it and
+/// the artifact is linked to the `.debug` file using `.gnu_debuglink`.
+pub fn process(artifact: &Path, env: &BuildEnv) -> GocarResult<()> {
+    let strip = env.profile.strip.unwrap_or_default();
+    let split = env.profile.split_debuginfo.unwrap_or(false);
+    if strip == Strip::None && !split {
+        return Ok(());
+    }
+
+    println!("   \u{1B}[32;1mStripping\u{1B}[0m {:?}", artifact.strip_prefix(env.strip_prefix).unwrap_or(artifact));
+    let debug_file = if split {
+        let debug_file = debug_file(artifact, env)?;
+        create_dir_all(debug_file.parent().unwrap())?;
+        Command::new(&*env.profile.objcopy())
+            .arg("--only-keep-debug")
+            .arg(artifact)
+            .arg(&debug_file)
+            .spawn()?
+            .wait()?
+            .failure_into_error()?;
+        Some(debug_file)
+    } else {
+        None
+    };
+
+    Command::new(&*env.profile.strip_program())
+        .arg(if strip == Strip::Symbols { "--strip-all" } else { "--strip-debug" })
+        .arg(artifact)
+        .spawn()?
+        .wait()?
+        .failure_into_error()?;
+
+    if let Some(debug_file) = debug_file {
+        let mut debuglink = OsString::from("--add-gnu-debuglink=");
+        debuglink.push(&debug_file);
+        Command::new(&*env.profile.objcopy())
+            .arg(debuglink)
+            .arg(artifact)
+            .spawn()?
+            .wait()?
+            .failure_into_error()?;
+    }
+
+    Ok(())
+}
+
+fn debug_file(artifact: &Path, env: &BuildEnv) -> GocarResult<PathBuf> {
+    if env.profile.debuginfo_layout.unwrap_or_default() == DebuginfoLayout::BuildId {
+        match build_id(artifact, env)? {
+            Some(id) => return Ok(env.target_dir.join(".build-id").join(&id[..2]).join(format!("{}.debug", &id[2..]))),
+            None => println!("     \u{1B}[33;1mWarning\u{1B}[0m: {} has no build ID, placing its debug information next to it", artifact.display()),
+        }
+    }
+
+    let mut debug_file = artifact.to_owned().into_os_string();
+    debug_file.push(".debug");
+    Ok(debug_file.into())
+}
+
+/// Reads the build ID from `.note.gnu.build-id` section of the artifact
+fn build_id(artifact: &Path, env: &BuildEnv) -> GocarResult<Option<String>> {
+    let mut note_file = artifact.to_owned().into_os_string();
+    note_file.push(".build-id");
+    let note_file = PathBuf::from(note_file);
+
+    Command::new(&*env.profile.objcopy())
+        .args(["-O", "binary", "--only-section=.note.gnu.build-id"])
+        .arg(artifact)
+        .arg(&note_file)
+        .spawn()?
+        .wait()?
+        .failure_into_error()?;
+    let note = std::fs::read(&note_file).err_ctx(|| (note_file.clone(), "read file"))?;
+    std::fs::remove_file(&note_file).err_ctx(|| (note_file, "remove file"))?;
+
+    Ok(parse_build_id(&note))
+}
+
+/// Parses the ELF note containing the build ID, in either byte order
+fn parse_build_id(note: &[u8]) -> Option<String> {
+    let field = |offset: usize| -> Option<[u8; 4]> { note.get(offset..(offset + 4))?.try_into().ok() };
+    let descsz = match field(0)? {
+        [4, 0, 0, 0] => u32::from_le_bytes(field(4)?),
+        [0, 0, 0, 4] => u32::from_be_bytes(field(4)?),
+        _ => return None,
+    };
+
+    if note.get(12..16)? != b"GNU\0" || descsz < 2 {
+        return None;
+    }
+
+    let id = note.get(16..(16 + descsz as usize))?;
+    Some(id.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_build_id;
+
+    #[test]
+    fn build_id() {
+        let mut note = vec![4, 0, 0, 0, 4, 0, 0, 0, 3, 0, 0, 0];
+        note.extend_from_slice(b"GNU\0");
+        note.extend_from_slice(&[0xab, 0xcd, 0x01, 0x23]);
+        assert_eq!(parse_build_id(&note).unwrap(), "abcd0123");
+
+        note[..8].copy_from_slice(&[0, 0, 0, 4, 0, 0, 0, 4]);
+        assert_eq!(parse_build_id(&note).unwrap(), "abcd0123");
+
+        assert_eq!(parse_build_id(&note[..18]), None);
+        assert_eq!(parse_build_id(&[]), None);
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use super::{Command, FsResult, GocarResult, write_if_changed};
+
+/// How to treat symbols exported from a shared library but not declared in its public headers
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportSymbols {
+    /// Symbols are not hidden nor checked
+    #[default]
+    All,
+    /// Unexpected exports are reported as a warning
+    Warn,
+    /// Unexpected exports fail the build
+    Deny,
+}
+
+#[derive(Debug)]
+pub struct UnexpectedExports {
+    pub library: PathBuf,
+    pub symbols: Vec<String>,
+}
+
+impl fmt::Display for UnexpectedExports {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "library {} exports symbols not declared in public headers: {}", self.library.display(), self.symbols.join(", "))
+    }
+}
+
+/// Name of the export macro for library `name`, e.g. `MESSAGE_API`
+pub fn export_macro(name: &Path) -> String {
+    let mut result = name
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect::<String>();
+    result.push_str("_API");
+    result
+}
+
+/// File name of the generated export header, e.g. `message_export.h`
+pub fn export_header_name(name: &Path) -> PathBuf {
+    let mut result = name.as_os_str().to_owned();
+    result.push("_export.h");
+    result.into()
+}
+
+/// Writes the header defining the export macro into `dir`, returning its path.
+///
+/// The header is only rewritten if the content changed, so it doesn't trigger recompilation.
+pub fn write_export_header(name: &Path, dir: &Path) -> FsResult<PathBuf> {
+    let api = export_macro(name);
+    let guard = format!("{}_EXPORT_H", &api[..(api.len() - "_API".len())]);
+    let content = format!("#ifndef {guard}\n#define {guard}\n\n#if defined(__GNUC__) || defined(__clang__)\n#define {api} __attribute__((visibility(\"default\")))\n#else\n#define {api}\n#endif\n\n#endif\n", guard = guard, api = api);
+
+    let path = dir.join(export_header_name(name));
+    write_if_changed(&path, content.as_bytes())?;
+
+    Ok(path)
+}
+
+/// Token of a preprocessed header
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Identifier(&'a str),
+    Literal,
+    Punct(&'a str),
+}
+
+/// Words which may be followed by parentheses in declarations without naming a function
+const NOT_NAMES: &[&str] = &[
+    "__asm", "__asm__", "__attribute", "__attribute__", "__declspec", "__typeof", "__typeof__", "_Alignas",
+    "_Alignof", "_Static_assert", "alignas", "alignof", "asm", "decltype", "noexcept", "sizeof", "static_assert",
+    "throw", "typeof", "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void",
+];
+
+fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_whitespace() {
+            c.len_utf8()
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            tokens.push(if c.is_ascii_digit() { Token::Literal } else { Token::Identifier(&rest[..len]) });
+            len
+        } else if c == '"' || c == '\'' {
+            let mut escaped = false;
+            let len = rest[1..].find(|next| {
+                let end = !escaped && next == c;
+                escaped = !escaped && next == '\\';
+                end
+            }).map_or(rest.len(), |end| end + 2);
+            tokens.push(Token::Literal);
+            len
+        } else {
+            let len = if rest.starts_with("::") { 2 } else { c.len_utf8() };
+            tokens.push(Token::Punct(&rest[..len]));
+            len
+        };
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+/// Kind of a block delimited by braces
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Scope {
+    /// File scope, namespace or `extern "C"` block
+    Namespace,
+    /// Body of a class, struct or union, where only static members and functions are symbols
+    Class,
+    /// Function body, initializer or enum, which doesn't declare symbols
+    Other,
+}
+
+/// Collects names of functions and variables declared in output of the preprocessor
+///
+/// Only declarations coming from `headers`, as told by line markers, are collected. Names are
+/// unqualified like those returned by `symbol_name`.
+fn declared_names(preprocessed: &str, headers: &HashSet<PathBuf>) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut scopes = vec![Scope::Namespace];
+    let mut statement = Vec::new();
+    // Declarators following the body of a class aren't collected
+    let mut after_class = false;
+    let mut in_header = false;
+    let mut files = HashMap::new();
+    for line in preprocessed.lines() {
+        if let Some(marker) = line.strip_prefix("# ") {
+            if let Some(file) = marker.split('"').nth(1) {
+                in_header = *files.entry(file).or_insert_with(|| headers.contains(Path::new(file)) || Path::new(file).canonicalize().map_or(false, |file| headers.contains(&file)));
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        for token in tokenize(line) {
+            let scope = *scopes.last().unwrap();
+            if scope == Scope::Other {
+                match token {
+                    Token::Punct("{") => scopes.push(Scope::Other),
+                    Token::Punct("}") => { scopes.pop(); },
+                    _ => (),
+                }
+                continue;
+            }
+
+            match token {
+                Token::Punct(";") => {
+                    if in_header && !after_class {
+                        declare(&statement, scope, &mut names);
+                    }
+                    statement.clear();
+                    after_class = false;
+                },
+                Token::Punct("{") => {
+                    let is = |word: &str| statement.contains(&Token::Identifier(word));
+                    let opens = if is("enum") || statement.contains(&Token::Punct("=")) {
+                        Scope::Other
+                    } else if is("namespace") || (is("extern") && statement.contains(&Token::Literal)) {
+                        Scope::Namespace
+                    } else if (is("class") || is("struct") || is("union")) && !without_attributes(&statement).contains(&Token::Punct("(")) {
+                        // Type information and virtual tables are named by the class, so are implicitly declared
+                        // constructors, destructor and assignment operators
+                        if let Some(name) = class_name(&without_attributes(&statement)).filter(|_| in_header) {
+                            names.insert(format!("~{}", name));
+                            names.insert(name);
+                            names.insert("operator=".to_owned());
+                        }
+                        Scope::Class
+                    } else {
+                        // Body of an inline function, declaring the function itself
+                        if in_header {
+                            declare(&statement, scope, &mut names);
+                        }
+                        Scope::Other
+                    };
+                    scopes.push(opens);
+                    statement.clear();
+                },
+                Token::Punct("}") => {
+                    if scopes.len() > 1 {
+                        after_class = scopes.pop() == Some(Scope::Class);
+                    }
+                    statement.clear();
+                },
+                Token::Punct(":") if matches!(statement.as_slice(), [Token::Identifier("public")] | [Token::Identifier("protected")] | [Token::Identifier("private")]) => statement.clear(),
+                token => statement.push(token),
+            }
+        }
+    }
+
+    names
+}
+
+/// Removes attributes like `__attribute__((visibility("default")))` of exported classes
+fn without_attributes<'a>(statement: &[Token<'a>]) -> Vec<Token<'a>> {
+    const ATTRIBUTES: &[&str] = &["__attribute", "__attribute__", "__declspec", "_Alignas", "alignas"];
+    let mut result = Vec::with_capacity(statement.len());
+    // Depth of parentheses of the skipped attribute, if any
+    let mut attribute_depth = None;
+    for (i, token) in statement.iter().enumerate() {
+        match (attribute_depth, token) {
+            (Some(depth), Token::Punct("(")) => attribute_depth = Some(depth + 1),
+            (Some(1), Token::Punct(")")) => attribute_depth = None,
+            (Some(depth), Token::Punct(")")) => attribute_depth = Some(depth - 1),
+            (Some(_), _) => (),
+            (None, Token::Identifier(word)) if ATTRIBUTES.contains(word) && statement.get(i + 1) == Some(&Token::Punct("(")) => attribute_depth = Some(0),
+            (None, token) => result.push(token.clone()),
+        }
+    }
+    result
+}
+
+/// Name of the class, struct or union whose body follows the statement
+fn class_name(statement: &[Token]) -> Option<String> {
+    let head = statement.split(|token| *token == Token::Punct(":")).next()?;
+    let keyword = head.iter().rposition(|token| matches!(token, Token::Identifier("class" | "struct" | "union")))?;
+    head[(keyword + 1)..]
+        .iter()
+        .take_while(|token| **token != Token::Punct("<"))
+        .filter_map(|token| match token {
+            Token::Identifier(name) if *name != "final" => Some((*name).to_owned()),
+            _ => None,
+        })
+        .last()
+}
+
+/// Adds names of functions and variables declared by the statement
+fn declare(statement: &[Token], scope: Scope, names: &mut HashSet<String>) {
+    let is = |word: &str| statement.contains(&Token::Identifier(word));
+    if is("typedef") || is("using") || ((is("class") || is("struct") || is("union") || is("enum")) && statement.len() <= 3) {
+        return;
+    }
+
+    // Functions are named by the first identifier followed by parentheses outside of other parentheses
+    let mut depth = 0usize;
+    for (i, token) in statement.iter().enumerate() {
+        match token {
+            Token::Punct("(") if depth == 0 => match function_name(&statement[..i]) {
+                Some(name) => {
+                    names.insert(name);
+                    return;
+                },
+                None => depth += 1,
+            },
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    // Variables are only symbols outside of classes unless they're static members
+    if scope == Scope::Class && !is("static") {
+        return;
+    }
+
+    let mut depth = 0usize;
+    let mut initializer = false;
+    let mut previous: Option<&Token> = None;
+    for token in statement.iter().chain(std::iter::once(&Token::Punct(","))) {
+        match token {
+            Token::Punct("(") | Token::Punct("[") => depth += 1,
+            Token::Punct(")") | Token::Punct("]") => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+
+        let ends_declarator = depth == 0 && matches!(token, Token::Punct(",") | Token::Punct("=")) || depth == 1 && *token == Token::Punct("[");
+        if ends_declarator && !initializer {
+            if let Some(Token::Identifier(name)) = previous {
+                if !NOT_NAMES.contains(name) {
+                    names.insert((*name).to_owned());
+                }
+            }
+        }
+        match token {
+            Token::Punct("=") if depth == 0 => initializer = true,
+            Token::Punct(",") if depth == 0 => initializer = false,
+            _ => (),
+        }
+        previous = Some(token);
+    }
+}
+
+/// Name of the function if `declarator` ends with one, unqualified like in `symbol_name`
+fn function_name(declarator: &[Token]) -> Option<String> {
+    // Operators are named like `operator+` or `operator bool`, `operator()` is just `operator`
+    if let Some(start) = declarator.iter().rposition(|token| *token == Token::Identifier("operator")) {
+        return Some(declarator[(start + 1)..].iter().fold(String::from("operator"), |mut name, token| {
+            match token {
+                Token::Punct(punct) => name.push_str(punct),
+                Token::Identifier(word) => {
+                    name.push(' ');
+                    name.push_str(word);
+                },
+                Token::Literal => (),
+            }
+            name
+        }));
+    }
+
+    match declarator {
+        [.., Token::Punct("~"), Token::Identifier(name)] => Some(format!("~{}", name)),
+        [.., Token::Identifier(name)] if !NOT_NAMES.contains(name) => Some((*name).to_owned()),
+        _ => None,
+    }
+}
+
+/// Prefixes of symbols the compiler generates for classes and functions
+///
+/// Symbols having them are attributed to the class or function following the prefix.
+const GENERATED_PREFIXES: &[&str] = &[
+    "typeinfo name for ", "typeinfo for ", "construction vtable for ", "vtable for ", "VTT for ", "guard variable for ",
+    "non-virtual thunk to ", "virtual thunk to ", "covariant return thunk to ",
+];
+
+/// Removes template arguments and ABI tags from a demangled name.
+///
+/// Angle brackets of operators like `operator<<` or `operator->` are kept.
+fn strip_template_arguments(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut depth = 0usize;
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        let starts_word = !result.ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+        if depth == 0 && starts_word && rest.starts_with("operator") {
+            let operator = &rest["operator".len()..];
+            let len = "operator".len() + operator.find(|c: char| !"<>=!+-*/%&|^~".contains(c)).unwrap_or(operator.len());
+            result.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+        if depth == 0 && rest.starts_with("[abi:") {
+            rest = rest.find(']').map_or("", |end| &rest[(end + 1)..]);
+            continue;
+        }
+
+        match c {
+            '<' => depth += 1,
+            '>' if depth > 0 => depth -= 1,
+            c if depth == 0 => result.push(c),
+            _ => (),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    result
+}
+
+/// Extracts the unqualified name from a line of `nm -D -C` output.
+///
+/// Absolute symbols are skipped since they are definitions of symbol versions. So are
+/// instantiations of standard library templates, which are exported by every library using
+/// them. Symbols generated for classes and functions, like `vtable for ns::Widget`, are named
+/// by the class or function.
+fn symbol_name(line: &str) -> Option<String> {
+    let mut parts = line.splitn(3, ' ');
+    if parts.nth(1)? == "A" {
+        return None;
+    }
+    let name = parts.next()?;
+    let name = name.split('@').next()?;
+    let name = GENERATED_PREFIXES.iter().find_map(|prefix| name.strip_prefix(prefix)).unwrap_or(name);
+    // Construction vtables are named like `B-in-C`, they're needed to construct `C`
+    let name = name.rsplit("-in-").next()?;
+    let name = strip_template_arguments(name);
+    let name = name.split('(').next()?;
+    // Return types of function templates precede the name, names of conversion operators contain a space
+    let qualified = match name.find("operator") {
+        Some(operator) => &name[name[..operator].rfind(' ').map_or(0, |space| space + 1)..],
+        None => name.rsplit(' ').next()?,
+    };
+    // Allocation functions are declared by `<new>`, which defines the placement ones inline
+    if qualified.starts_with("std::") || qualified.starts_with("__gnu_cxx::") || qualified.starts_with("operator new") || qualified.starts_with("operator delete") {
+        return None;
+    }
+    Some(qualified.rsplit("::").next()?.trim().to_owned())
+}
+
+/// Returns names from `nm -D -C` output which aren't `declared`, sorted and deduplicated.
+fn unexpected_exports(nm_output: &str, declared: &HashSet<String>) -> Vec<String> {
+    let mut unexpected = nm_output
+        .lines()
+        .filter_map(symbol_name)
+        .filter(|name| !name.is_empty() && !declared.contains(name))
+        .collect::<Vec<_>>();
+    unexpected.sort();
+    unexpected.dedup();
+    unexpected
+}
+
+/// Returns symbols exported from the shared library which aren't declared in public headers.
+///
+/// `preprocessed` is output of the preprocessor for the headers, whose canonical paths are
+/// `public_headers`. Declarations from headers they include aren't counted.
+pub fn check_exports(library: &Path, preprocessed: &str, public_headers: &HashSet<PathBuf>) -> GocarResult<Vec<String>> {
+    let declared = declared_names(preprocessed, public_headers);
+    let output = Command::new("nm")
+        .arg("-D")
+        .arg("-C")
+        .arg("--defined-only")
+        .arg(library)
+        .output()?;
+
+    Ok(unexpected_exports(&String::from_utf8_lossy(&output), &declared))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+    use super::{declared_names, export_macro, symbol_name, unexpected_exports};
+
+    #[test]
+    fn macro_name() {
+        assert_eq!(export_macro(Path::new("message")), "MESSAGE_API");
+        assert_eq!(export_macro(Path::new("foo-bar")), "FOO_BAR_API");
+    }
+
+    #[test]
+    fn declarations() {
+        let preprocessed = r#"
+# 1 "/project/src/foo.h"
+typedef struct foo foo_t;
+struct point { int x; int y; };
+enum color { RED, GREEN };
+extern int counter, limits[4];
+__attribute__((visibility("default"))) void print_message(const char *msg);
+static inline int square(int x) { int helper = x; return helper * x; }
+extern "C" {
+# 1 "/usr/include/stdio.h" 1 3 4
+int printf(const char *format, ...);
+# 10 "/project/src/foo.h" 2
+const char *version(void);
+}
+namespace ns {
+class Widget : public Base {
+public:
+    Widget(int size);
+    ~Widget();
+    bool operator==(const Widget &other) const;
+    static int instances;
+    int size;
+private:
+    void resize(int size) { this->size = size; }
+};
+}
+"#;
+        let headers = std::iter::once(PathBuf::from("/project/src/foo.h")).collect::<HashSet<_>>();
+        let mut names = declared_names(preprocessed, &headers).into_iter().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["Widget", "counter", "instances", "limits", "operator=", "operator==", "point", "print_message", "resize", "square", "version", "~Widget", "~point"]);
+    }
+
+    #[test]
+    fn nm_output() {
+        assert_eq!(symbol_name("0000000000001109 T print_message@@MESSAGE_1").as_deref(), Some("print_message"));
+        assert_eq!(symbol_name("0000000000001109 T ns::foo(int, char const*)").as_deref(), Some("foo"));
+        assert_eq!(symbol_name("0000000000004010 B counter").as_deref(), Some("counter"));
+        assert_eq!(symbol_name("0000000000000000 A MESSAGE_1"), None);
+    }
+
+    #[test]
+    fn cpp_nm_output() {
+        let nm_output = "\
+00000000000050b0 u ns::Box<int>::value
+000000000000257e W ns::Box<int>::get() const
+000000000000256f W int ns::twice<int>(int)
+000000000000255c T ns::operator<(ns::Widget const&, ns::Widget const&)
+00000000000022a0 T ns::Widget::g()
+0000000000002592 W ns::Widget::~Widget()
+0000000000004d58 V typeinfo for ns::Widget
+0000000000003030 V typeinfo name for ns::Widget
+0000000000004c60 V vtable for ns::Widget
+0000000000004c30 V VTT for ns::Mid
+0000000000004d28 V construction vtable for ns::Base-in-ns::Mid
+00000000000022af T non-virtual thunk to ns::Widget::g()
+00000000000023b3 T virtual thunk to ns::Mid::~Mid()
+00000000000051a0 u guard variable for ns::name[abi:cxx11]()::n
+00000000000027f6 W std::vector<int, std::allocator<int> >::_M_realloc_insert<int const&>(__gnu_cxx::__normal_iterator<int*, std::vector<int, std::allocator<int> > >, int const&)
+0000000000002810 W void std::__introsort_loop<int*, long>(int*, int*, long)
+0000000000002600 W operator new(unsigned long, void*)
+0000000000002224 T ns::helper(int)
+";
+        let names = nm_output.lines().filter_map(symbol_name).collect::<Vec<_>>();
+        assert_eq!(names, ["value", "get", "twice", "operator<", "g", "~Widget", "Widget", "Widget", "Widget", "Mid", "Mid", "g", "~Mid", "name", "helper"]);
+
+        let preprocessed = r#"
+# 1 "/project/src/widget.hpp"
+namespace ns {
+class __attribute__((visibility("default"))) Widget final : public Base, public Other {
+public:
+    int g() override;
+};
+struct Mid : virtual Base { ~Mid(); };
+template<typename T> struct Box { static T value; T get() const; };
+template<typename T> T twice(T x) { return x + x; }
+bool operator<(const Widget &, const Widget &);
+inline std::string &name() { static std::string n("x"); return n; }
+}
+"#;
+        let headers = std::iter::once(PathBuf::from("/project/src/widget.hpp")).collect::<HashSet<_>>();
+        assert_eq!(unexpected_exports(nm_output, &declared_names(preprocessed, &headers)), ["helper"]);
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::{Path, PathBuf};
+use super::{Command, FsResult, GocarResult, ResultExt, create_dir_all, get_file_mtime, is_older};
+
+/// Rule turning non-C inputs into C sources and headers
+///
+/// The command runs once for each input file matching `input`. Generated files are placed in
+/// the `gen` subdirectory of the target directory, so they take part in scanning the same way
+/// as files generated by the build script. Generated units are also compiled into every target,
+/// since there may be no header leading to them.
+#[derive(Debug, Deserialize)]
+pub struct Generator {
+    /// Glob matching input files relative to the project directory, e.g. `src/**/*.y`
+    pub input: String,
+    /// Command and its arguments
+    ///
+    /// `{input}`, `{name}`, `{stem}` and `{out_dir}` are replaced by the path of the input file,
+    /// its file name, its file name without extension and the directory for generated files.
+    pub command: Vec<String>,
+    /// Generated files relative to the directory for generated files, with the same placeholders
+    pub outputs: Vec<String>,
+    /// Writes standard output of the command into the first output, e.g. for `xxd -i`
+    #[serde(default)]
+    pub capture_stdout: bool,
+}
+
+impl Generator {
+    /// Runs the command for every input which is newer than any of its outputs.
+    ///
+    /// Returns outputs for all inputs, including those which didn't need regeneration.
+    pub fn run(&self, project_dir: &Path, gen_dir: &Path, strip_prefix: &Path) -> GocarResult<Vec<PathBuf>> {
+        let mut all_outputs = Vec::new();
+        for input in glob(project_dir, &self.input)? {
+            let outputs = self.outputs.iter().map(|output| gen_dir.join(substitute(output, &input, gen_dir))).collect::<Vec<_>>();
+            let regenerate = self.needs_regeneration(&input, &outputs)?;
+            all_outputs.extend_from_slice(&outputs);
+            if !regenerate {
+                continue;
+            }
+
+            for output in &outputs {
+                create_dir_all(output.parent().unwrap())?;
+            }
+
+            let (program, args) = match self.command.split_first() {
+                Some(command) => command,
+                None => continue,
+            };
+
+            println!("  \u{1B}[32;1mGenerating\u{1B}[0m {:?}", input.strip_prefix(strip_prefix).unwrap_or(&input));
+            let command = Command::new(substitute(program, &input, gen_dir))
+                .current_dir(project_dir)
+                .args(args.iter().map(|arg| substitute(arg, &input, gen_dir)));
+
+            match (self.capture_stdout, outputs.first()) {
+                (true, Some(output)) => {
+                    let stdout = command.output()?;
+                    std::fs::write(output, stdout).err_ctx(|| (output.clone(), "write file"))?;
+                },
+                _ => command.spawn()?.wait()?.failure_into_error()?,
+            }
+        }
+
+        Ok(all_outputs)
+    }
+
+    fn needs_regeneration(&self, input: &Path, outputs: &[PathBuf]) -> FsResult<bool> {
+        for output in outputs {
+            match get_file_mtime(output)? {
+                Some(mtime) if !is_older(mtime, std::iter::once(input))? => (),
+                _ => return Ok(true),
+            }
+        }
+
+        Ok(outputs.is_empty())
+    }
+}
+
+/// Replaces placeholders describing the input file in `template`
+fn substitute(template: &str, input: &Path, gen_dir: &Path) -> String {
+    let name = input.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let stem = input.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+
+    template
+        .replace("{input}", &input.to_string_lossy())
+        .replace("{name}", &name)
+        .replace("{stem}", &stem)
+        .replace("{out_dir}", &gen_dir.to_string_lossy())
+}
+
+/// Returns files matching the pattern, sorted.
+///
+/// `*` matches any part of a file name, `?` a single character and `**` any number of
+/// directories.
+fn glob(dir: &Path, pattern: &str) -> FsResult<Vec<PathBuf>> {
+    let components = pattern.split('/').filter(|component| !component.is_empty()).collect::<Vec<_>>();
+    let mut files = Vec::new();
+    expand_glob(dir, &components, &mut files)?;
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+fn expand_glob(dir: &Path, components: &[&str], files: &mut Vec<PathBuf>) -> FsResult<()> {
+    let (first, rest) = match components.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+
+    if *first == "**" {
+        expand_glob(dir, rest, files)?;
+        for entry in std::fs::read_dir(dir).err_ctx(|| (dir.to_owned(), "read directory"))? {
+            let path = entry.err_ctx(|| (dir.to_owned(), "read directory"))?.path();
+            if path.is_dir() {
+                expand_glob(&path, components, files)?;
+            }
+        }
+    } else if !first.contains(['*', '?']) {
+        let path = dir.join(first);
+        if rest.is_empty() && path.is_file() {
+            files.push(path);
+        } else if path.is_dir() {
+            expand_glob(&path, rest, files)?;
+        }
+    } else {
+        for entry in std::fs::read_dir(dir).err_ctx(|| (dir.to_owned(), "read directory"))? {
+            let path = entry.err_ctx(|| (dir.to_owned(), "read directory"))?.path();
+            let matches = path.file_name().map_or(false, |name| matches_component(first.as_bytes(), name.to_string_lossy().as_bytes()));
+            if !matches {
+                continue;
+            }
+
+            if rest.is_empty() && path.is_file() {
+                files.push(path);
+            } else if path.is_dir() {
+                expand_glob(&path, rest, files)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn matches_component(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', pattern_rest)), _) => matches_component(pattern_rest, name) || (!name.is_empty() && matches_component(pattern, &name[1..])),
+        (Some((b'?', pattern_rest)), Some((_, name_rest))) => matches_component(pattern_rest, name_rest),
+        (Some((p, pattern_rest)), Some((n, name_rest))) => p == n && matches_component(pattern_rest, name_rest),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{matches_component, substitute};
+
+    #[test]
+    fn glob_component() {
+        assert!(matches_component(b"*.y", b"parser.y"));
+        assert!(matches_component(b"*.y", b".y"));
+        assert!(!matches_component(b"*.y", b"parser.yy"));
+        assert!(matches_component(b"msg?.proto", b"msg1.proto"));
+        assert!(!matches_component(b"msg?.proto", b"msg.proto"));
+        assert!(matches_component(b"a*b*c", b"aXbYc"));
+    }
+
+    #[test]
+    fn placeholders() {
+        let input = Path::new("/project/src/parser.y");
+        let gen_dir = Path::new("/project/target/debug/gen");
+        assert_eq!(substitute("{out_dir}/{stem}.tab.c", input, gen_dir), "/project/target/debug/gen/parser.tab.c");
+        assert_eq!(substitute("{input}", input, gen_dir), "/project/src/parser.y");
+        assert_eq!(substitute("{name}.c", input, gen_dir), "parser.y.c");
+    }
+}
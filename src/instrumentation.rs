@@ -0,0 +1,264 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use super::{Command, CommandError, FsResult, Profile, ResultExt};
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Sanitizer {
+    Address,
+    Undefined,
+    Thread,
+    Memory,
+}
+
+impl Sanitizer {
+    fn name(self) -> &'static str {
+        match self {
+            Sanitizer::Address => "address",
+            Sanitizer::Undefined => "undefined",
+            Sanitizer::Thread => "thread",
+            Sanitizer::Memory => "memory",
+        }
+    }
+
+    fn short_name(self) -> &'static str {
+        match self {
+            Sanitizer::Address => "asan",
+            Sanitizer::Undefined => "ubsan",
+            Sanitizer::Thread => "tsan",
+            Sanitizer::Memory => "msan",
+        }
+    }
+
+    /// Address, thread and memory sanitizers can't be combined with each other.
+    fn is_compatible_with(self, other: Sanitizer) -> bool {
+        self == other || self == Sanitizer::Undefined || other == Sanitizer::Undefined
+    }
+}
+
+impl std::str::FromStr for Sanitizer {
+    type Err = UnknownSanitizer;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "address" => Ok(Sanitizer::Address),
+            "undefined" => Ok(Sanitizer::Undefined),
+            "thread" => Ok(Sanitizer::Thread),
+            "memory" => Ok(Sanitizer::Memory),
+            _ => Err(UnknownSanitizer(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Sanitizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownSanitizer(String);
+
+impl fmt::Display for UnknownSanitizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown sanitizer {}, expected one of address, undefined, thread, memory", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct IncompatibleSanitizers(pub Sanitizer, pub Sanitizer);
+
+impl fmt::Display for IncompatibleSanitizers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sanitizers {} and {} can't be used together", self.0, self.1)
+    }
+}
+
+/// Instrumentation added on top of the selected profile
+///
+/// It's applied to every compiled unit and every linked target including dependencies.
+#[derive(Debug, Default, Clone)]
+pub struct Instrumentation {
+    sanitizers: Vec<Sanitizer>,
+    coverage: bool,
+}
+
+impl Instrumentation {
+    pub fn add_sanitizer(&mut self, sanitizer: Sanitizer) -> Result<(), IncompatibleSanitizers> {
+        if let Some(incompatible) = self.sanitizers.iter().find(|enabled| !enabled.is_compatible_with(sanitizer)) {
+            return Err(IncompatibleSanitizers(*incompatible, sanitizer));
+        }
+
+        if !self.sanitizers.contains(&sanitizer) {
+            self.sanitizers.push(sanitizer);
+        }
+
+        Ok(())
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = true;
+    }
+
+    pub fn coverage(&self) -> bool {
+        self.coverage
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sanitizers.is_empty() && !self.coverage
+    }
+
+    /// Name of the target subdirectory for given profile
+    ///
+    /// Instrumented builds get their own directory so that they don't clobber normal builds.
+    pub fn profile_dir_name(&self, profile_name: &str) -> String {
+        let mut name = profile_name.to_owned();
+        for sanitizer in &self.sanitizers {
+            name.push('-');
+            name.push_str(sanitizer.short_name());
+        }
+
+        if self.coverage {
+            name.push_str("-coverage");
+        }
+
+        name
+    }
+
+    /// Returns the profile with instrumentation options added.
+    pub fn apply<'a>(&self, profile: &'a Profile) -> Cow<'a, Profile> {
+        if self.is_empty() {
+            return Cow::Borrowed(profile);
+        }
+
+        let mut profile = profile.clone();
+        if !self.sanitizers.is_empty() {
+            let names = self.sanitizers.iter().map(|sanitizer| sanitizer.name()).collect::<Vec<_>>();
+            let sanitize = PathBuf::from(format!("-fsanitize={}", names.join(",")));
+            profile.compile_options.common.push(sanitize.clone());
+            profile.compile_options.common.push("-fno-omit-frame-pointer".into());
+            profile.link_options.push(sanitize);
+        }
+
+        if self.coverage {
+            profile.compile_options.common.push("--coverage".into());
+            profile.link_options.push("--coverage".into());
+        }
+
+        Cow::Owned(profile)
+    }
+}
+
+fn find_gcda_files(dir: &Path, files: &mut Vec<PathBuf>) -> FsResult<()> {
+    for entry in std::fs::read_dir(dir).err_ctx(|| (dir.to_owned(), "read directory"))? {
+        let path = entry.err_ctx(|| (dir.to_owned(), "read directory"))?.path();
+        if path.is_dir() {
+            find_gcda_files(&path, files)?;
+        } else if path.extension().map_or(false, |ext| ext == "gcda") {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes coverage data left over from previous runs.
+pub fn clear_coverage_data<P: AsRef<Path>>(target_dir: P) -> FsResult<()> {
+    let mut files = Vec::new();
+    find_gcda_files(target_dir.as_ref(), &mut files)?;
+    for file in files {
+        std::fs::remove_file(&file).err_ctx(|| (file, "remove file"))?;
+    }
+
+    Ok(())
+}
+
+/// Coverage of a single source file as reported by `gcov`
+#[derive(Debug)]
+pub struct FileCoverage {
+    pub source: PathBuf,
+    pub lines: u64,
+    pub executed: u64,
+}
+
+fn parse_gcov_summary(output: &str, project_dir: &Path, coverage: &mut Vec<FileCoverage>) {
+    let mut source = None;
+    for line in output.lines() {
+        if let Some(file) = line.strip_prefix("File '").and_then(|line| line.strip_suffix('\'')) {
+            source = Some(PathBuf::from(file));
+        } else if let Some(summary) = line.strip_prefix("Lines executed:") {
+            let file = match source.take() {
+                Some(file) if file.starts_with(project_dir) => file,
+                _ => continue,
+            };
+
+            let mut parts = summary.splitn(2, "% of ");
+            let percent = parts.next().and_then(|percent| percent.parse::<f64>().ok());
+            let lines = parts.next().and_then(|lines| lines.trim().parse::<u64>().ok());
+            if let (Some(percent), Some(lines)) = (percent, lines) {
+                let executed = (percent * lines as f64 / 100.0).round() as u64;
+                match coverage.iter_mut().find(|item| item.source == file) {
+                    Some(item) => item.executed = item.executed.max(executed),
+                    None => coverage.push(FileCoverage { source: file, lines, executed, }),
+                }
+            }
+        }
+    }
+}
+
+/// Collects `.gcda` files produced by running instrumented binaries and summarizes them.
+///
+/// The summary only includes sources inside of `project_dir`. If `lcov` is installed an lcov
+/// tracefile is written to `target_dir/coverage.info` as well.
+pub fn collect_coverage<TP: AsRef<Path>, PP: AsRef<Path>>(target_dir: TP, project_dir: PP) -> super::GocarResult<Vec<FileCoverage>> {
+    let target_dir = target_dir.as_ref();
+    let mut files = Vec::new();
+    find_gcda_files(target_dir, &mut files)?;
+    files.sort();
+
+    let mut coverage = Vec::new();
+    for file in files {
+        let output = Command::new("gcov")
+            .arg("-n")
+            .arg(&file)
+            .output()?;
+
+        parse_gcov_summary(&String::from_utf8_lossy(&output), project_dir.as_ref(), &mut coverage);
+    }
+    coverage.sort_by(|a, b| a.source.cmp(&b.source));
+
+    let tracefile = target_dir.join("coverage.info");
+    match Command::new("lcov").arg("--quiet").arg("--capture").arg("--directory").arg(target_dir).arg("--output-file").arg(&tracefile).spawn() {
+        Ok(lcov) => lcov.wait()?.failure_into_error()?,
+        Err(CommandError::Spawn(ref error)) if error.error.kind() == std::io::ErrorKind::NotFound => (),
+        Err(error) => return Err(error.into()),
+    }
+
+    Ok(coverage)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{Instrumentation, Sanitizer, parse_gcov_summary};
+
+    #[test]
+    fn incompatible_sanitizers() {
+        let mut instrumentation = Instrumentation::default();
+        instrumentation.add_sanitizer(Sanitizer::Address).unwrap();
+        instrumentation.add_sanitizer(Sanitizer::Undefined).unwrap();
+        assert!(instrumentation.add_sanitizer(Sanitizer::Thread).is_err());
+        assert_eq!(instrumentation.profile_dir_name("debug"), "debug-asan-ubsan");
+    }
+
+    #[test]
+    fn gcov_summary() {
+        let output = "File '/project/src/main.c'\nLines executed:50.00% of 8\nCreating 'main.c.gcov'\n\nFile '/usr/include/stdio.h'\nLines executed:100.00% of 1\n";
+        let mut coverage = Vec::new();
+        parse_gcov_summary(output, Path::new("/project"), &mut coverage);
+        assert_eq!(coverage.len(), 1);
+        assert_eq!(coverage[0].source, Path::new("/project/src/main.c"));
+        assert_eq!(coverage[0].lines, 8);
+        assert_eq!(coverage[0].executed, 4);
+    }
+}
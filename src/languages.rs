@@ -0,0 +1,364 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+
+/// Definition of a language which can be compiled and linked
+///
+/// Languages are configured as `[language.<name>]`. Built-in languages are `c`, `cpp`, `cppm`
+/// (C++ module interface units) and `asm`, defining a language with the same name replaces the built-in one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Language {
+    /// Name of the language, same as the key in the `language` table
+    #[serde(skip)]
+    pub name: String,
+    /// Extensions of units written in the language, without the dot
+    pub extensions: Vec<String>,
+    /// Compiler used unless the profile or the environment variable sets one
+    pub compiler: PathBuf,
+    /// Environment variable overriding the default compiler, e.g. `CC`
+    #[serde(default)]
+    pub compiler_env: Option<String>,
+    /// Prefix of keys configuring the language, e.g. `objc` for `objc_compiler` and
+    /// `objc_compile_options`; defaults to the name
+    #[serde(default)]
+    pub options_key: Option<String>,
+    /// Options of the compiler printing dependencies of a unit in make format
+    ///
+    /// If empty, units aren't scanned for headers.
+    #[serde(default = "default_scan_options")]
+    pub scan_options: Vec<String>,
+    /// The compiler of the language with the highest precedence among linked units drives
+    /// the linking
+    #[serde(default)]
+    pub link_precedence: u32,
+    /// Options key of a language whose configured compiler is used if the profile doesn't set
+    /// the compiler of this language
+    #[serde(default)]
+    pub compiler_fallback: Option<String>,
+    /// Value of `-x` option used to precompile headers for units of the language, e.g.
+    /// `c++-header`; precompiled headers aren't used if unset
+    #[serde(default)]
+    pub header_language: Option<String>,
+    /// Units of the language can be included into a single file in unity builds
+    #[serde(default)]
+    pub unity: bool,
+    /// Options always passed to the compiler for units of the language, e.g. `-x c++`
+    #[serde(default)]
+    pub required_options: Vec<PathBuf>,
+    /// Units of the language are module interface units
+    ///
+    /// If modules are enabled, they're found anywhere in the project and compiled into every
+    /// target which imports them.
+    #[serde(default)]
+    pub module_interfaces: bool,
+    /// Command printing modules provided and required by a unit in P1689 format
+    ///
+    /// `{compiler}`, `{options}`, `{input}` and `{object}` are replaced by the compiler, include
+    /// directories and compile options, the unit and its object. If empty, units aren't scanned
+    /// for modules.
+    #[serde(default)]
+    pub module_scan: Vec<String>,
+    /// Options passed when compiling any unit with modules enabled, `{mapper}` is replaced by
+    /// a file mapping names of modules to their BMI files, one `NAME PATH` per line
+    #[serde(default)]
+    pub module_options: Vec<String>,
+    /// Options passed when compiling a module interface unit, `{bmi}` is replaced by its BMI
+    #[serde(default)]
+    pub module_interface_options: Vec<String>,
+    /// Options passed for every imported module, `{name}` and `{bmi}` are replaced by the name
+    /// of the module and its BMI
+    #[serde(default)]
+    pub module_import_options: Vec<String>,
+}
+
+fn default_scan_options() -> Vec<String> {
+    vec!["-MM".to_owned()]
+}
+
+fn strings(strings: &[&str]) -> Vec<String> {
+    strings.iter().map(|string| (*string).to_owned()).collect()
+}
+
+impl Language {
+    fn builtin(name: &str, extensions: &[&str], compiler: &str, compiler_env: &str, link_precedence: u32) -> Self {
+        Language {
+            name: name.to_owned(),
+            extensions: strings(extensions),
+            compiler: compiler.into(),
+            compiler_env: Some(compiler_env.to_owned()),
+            options_key: None,
+            scan_options: default_scan_options(),
+            link_precedence,
+            compiler_fallback: None,
+            header_language: None,
+            unity: false,
+            required_options: Vec::new(),
+            module_interfaces: false,
+            module_scan: Vec::new(),
+            module_options: Vec::new(),
+            module_interface_options: Vec::new(),
+            module_import_options: Vec::new(),
+        }
+    }
+
+    /// Sets up module support using GCC options
+    fn with_gcc_modules(mut self) -> Self {
+        self.module_scan = strings(&["{compiler}", "{options}", "-fmodules-ts", "-fdeps-format=p1689r5", "-fdeps-file=/dev/stdout", "-fdeps-target={object}", "-M", "-MF", "/dev/null", "-E", "{input}", "-o", "/dev/null"]);
+        self.module_options = strings(&["-fmodules-ts", "-fmodule-mapper={mapper}"]);
+        self
+    }
+
+    fn with_header_language(mut self, header_language: &str) -> Self {
+        self.header_language = Some(header_language.to_owned());
+        self
+    }
+
+    fn with_unity(mut self) -> Self {
+        self.unity = true;
+        self
+    }
+
+    /// Prefix of configuration keys of the language
+    pub fn options_key(&self) -> &str {
+        self.options_key.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Compiler used when the profile doesn't configure one
+    pub fn default_compiler(&self) -> Cow<'_, Path> {
+        self.compiler_env
+            .as_ref()
+            .and_then(std::env::var_os)
+            .map_or_else(|| Cow::Borrowed(&*self.compiler), |compiler| Cow::Owned(compiler.into()))
+    }
+}
+
+/// There's no language with the name
+#[derive(Debug)]
+pub struct UnknownLanguage(pub String);
+
+impl fmt::Display for UnknownLanguage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown language {}", self.0)
+    }
+}
+
+/// Per-language key doesn't configure any known language, e.g. misspelled `cxx_compile_options`
+#[derive(Debug)]
+pub struct UnknownLanguageKey(pub String);
+
+impl fmt::Display for UnknownLanguageKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} doesn't match options key of any language", self.0)
+    }
+}
+
+/// Registry of known languages
+#[derive(Debug, Clone)]
+pub struct Languages {
+    languages: Vec<Language>,
+}
+
+impl Default for Languages {
+    fn default() -> Self {
+        // Why not "C" as well? According to https://stackoverflow.com/a/1546107 it means C++ but I
+        // find it highly confusing. I'm not supporting it until there's a big pressure.
+        let mut asm = Language::builtin("asm", &["S", "s"], "cc", "CC", 0);
+        asm.compiler_fallback = Some("c".to_owned());
+        // Interface units share compiler and options with other C++ units
+        let mut cppm = Language::builtin("cppm", &["cppm", "ixx"], "c++", "CXX", 1).with_gcc_modules().with_header_language("c++-header");
+        cppm.options_key = Some("cpp".to_owned());
+        cppm.required_options = vec!["-x".into(), "c++".into()];
+        cppm.module_interfaces = true;
+
+        Languages {
+            languages: vec![
+                Language::builtin("c", &["c"], "cc", "CC", 0).with_header_language("c-header").with_unity(),
+                Language::builtin("cpp", &["cpp", "cc", "cxx", "CPP", "CC", "CXX"], "c++", "CXX", 1).with_gcc_modules().with_header_language("c++-header").with_unity(),
+                cppm,
+                asm,
+            ],
+        }
+    }
+}
+
+impl Languages {
+    /// Returns the language named `name`.
+    pub fn get(&self, name: &str) -> Result<&Language, UnknownLanguage> {
+        self.languages
+            .iter()
+            .find(|language| language.name == name)
+            .ok_or_else(|| UnknownLanguage(name.to_owned()))
+    }
+
+    /// Returns the language of the unit based on its extension.
+    pub fn determine_from_file<P: AsRef<Path>>(&self, file: P) -> Option<&Language> {
+        let extension = file.as_ref().extension()?;
+        self.languages.iter().find(|language| language.extensions.iter().any(|known| extension == &**known))
+    }
+
+    /// Returns the language whose compiler should link units of given languages.
+    pub fn linker<'a, I: IntoIterator<Item=&'a Language>>(&'a self, languages: I) -> Result<&'a Language, UnknownLanguage> {
+        Ok(languages.into_iter().fold(self.get("c")?, |best, language| if language.link_precedence > best.link_precedence { language } else { best }))
+    }
+
+    /// Checks that keys of per-language values configured as `K::PREFIX<key>K::SUFFIX` are
+    /// options keys of known languages, so that misspelled keys aren't silently ignored.
+    pub fn check_options_keys<'a, K: LanguageKeys, I: IntoIterator<Item=&'a String>>(&self, keys: I) -> Result<(), UnknownLanguageKey> {
+        for key in keys {
+            if !self.languages.iter().any(|language| language.options_key() == key) {
+                return Err(UnknownLanguageKey(format!("{}{}{}", K::PREFIX, key, K::SUFFIX)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// All extensions of units
+    pub fn extensions(&self) -> impl Iterator<Item=&str> {
+        self.languages.iter().flat_map(|language| language.extensions.iter().map(AsRef::as_ref))
+    }
+}
+
+impl<'de> Deserialize<'de> for Languages {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let configured = HashMap::<String, Language>::deserialize(deserializer)?;
+        let mut result = Languages::default();
+        let mut configured = configured.into_iter().collect::<Vec<_>>();
+        configured.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, mut language) in configured {
+            language.name = name;
+            result.languages.retain(|builtin| builtin.name != language.name);
+            result.languages.push(language);
+        }
+        Ok(result)
+    }
+}
+
+/// Selects keys of a flattened table configuring languages, e.g. `<key>_compile_options`
+pub trait LanguageKeys {
+    /// Key applying to all languages, if any
+    const COMMON: Option<&'static str>;
+    const PREFIX: &'static str;
+    const SUFFIX: &'static str;
+}
+
+/// `compile_options` and `<key>_compile_options` of targets and profiles
+pub enum CompileOptionKeys {}
+
+impl LanguageKeys for CompileOptionKeys {
+    const COMMON: Option<&'static str> = Some("compile_options");
+    const PREFIX: &'static str = "";
+    const SUFFIX: &'static str = "_compile_options";
+}
+
+/// `add_compile_options` and `add_<key>_compile_options` of the project
+pub enum AddCompileOptionKeys {}
+
+impl LanguageKeys for AddCompileOptionKeys {
+    const COMMON: Option<&'static str> = Some("add_compile_options");
+    const PREFIX: &'static str = "add_";
+    const SUFFIX: &'static str = "_compile_options";
+}
+
+/// `<key>_compiler` of profiles
+pub enum CompilerKeys {}
+
+impl LanguageKeys for CompilerKeys {
+    const COMMON: Option<&'static str> = None;
+    const PREFIX: &'static str = "";
+    const SUFFIX: &'static str = "_compiler";
+}
+
+/// Values configured per language, deserialized from `PREFIX<key>SUFFIX` keys
+///
+/// It's supposed to be flattened into a table, other keys of the table are ignored. The languages
+/// aren't known while deserializing, so keys are checked later using `Languages::check_options_keys`.
+pub struct PerLanguage<K, V> {
+    pub common: Option<V>,
+    pub languages: HashMap<String, V>,
+    _keys: PhantomData<K>,
+}
+
+impl<'de, K: LanguageKeys, V: Deserialize<'de>> Deserialize<'de> for PerLanguage<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PerLanguageVisitor<K, V>(PhantomData<(K, V)>);
+
+        impl<'de, K: LanguageKeys, V: Deserialize<'de>> Visitor<'de> for PerLanguageVisitor<K, V> {
+            type Value = PerLanguage<K, V>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a table")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut result = PerLanguage { common: None, languages: HashMap::new(), _keys: PhantomData, };
+                while let Some(key) = map.next_key::<String>()? {
+                    if Some(&*key) == K::COMMON {
+                        result.common = Some(map.next_value()?);
+                        continue;
+                    }
+
+                    let language = key.strip_prefix(K::PREFIX).and_then(|key| key.strip_suffix(K::SUFFIX)).filter(|language| !language.is_empty());
+                    match language {
+                        Some(language) => {
+                            result.languages.insert(language.to_owned(), map.next_value()?);
+                        },
+                        None => {
+                            map.next_value::<IgnoredAny>()?;
+                        },
+                    }
+                }
+                Ok(result)
+            }
+        }
+
+        deserializer.deserialize_map(PerLanguageVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::Languages;
+
+    #[test]
+    fn builtin() {
+        let languages = Languages::default();
+        assert_eq!(languages.determine_from_file("a.c").unwrap().name, "c");
+        assert_eq!(languages.determine_from_file("a.cxx").unwrap().name, "cpp");
+        assert_eq!(languages.determine_from_file("a.S").unwrap().name, "asm");
+        assert_eq!(languages.determine_from_file("a.ixx").unwrap().options_key(), "cpp");
+        assert!(languages.determine_from_file("a.m").is_none());
+        assert!(languages.determine_from_file("Makefile").is_none());
+        assert_eq!(languages.linker(vec![languages.get("asm").unwrap(), languages.get("cpp").unwrap(), languages.get("c").unwrap()]).unwrap().name, "cpp");
+        assert_eq!(languages.linker(vec![]).unwrap().name, "c");
+        assert_eq!(languages.get("rust").unwrap_err().to_string(), "unknown language rust");
+    }
+
+    #[test]
+    fn configured() {
+        let languages = toml::from_str::<std::collections::HashMap<String, Languages>>(r#"
+            [language.objc]
+            extensions = ["m"]
+            compiler = "clang"
+            compiler_env = "OBJC"
+
+            [language.fortran]
+            extensions = ["f90"]
+            compiler = "gfortran"
+            scan_options = []
+            link_precedence = 2
+        "#).unwrap().remove("language").unwrap();
+
+        let objc = languages.determine_from_file("main.m").unwrap();
+        assert_eq!(objc.name, "objc");
+        assert_eq!(objc.options_key(), "objc");
+        assert_eq!(objc.compiler, Path::new("clang"));
+        assert_eq!(objc.scan_options, ["-MM"]);
+        assert_eq!(languages.linker(vec![languages.get("cpp").unwrap(), languages.get("fortran").unwrap()]).unwrap().name, "fortran");
+        assert_eq!(languages.determine_from_file("a.c").unwrap().name, "c");
+    }
+}
@@ -2,7 +2,8 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::io::BufRead;
 use std::path::{Path, PathBuf};
@@ -11,6 +12,38 @@ use std::time::SystemTime;
 use std::fmt;
 
 mod objs;
+mod instrumentation;
+mod exports;
+mod test_runner;
+mod bench;
+mod build_script;
+mod generators;
+mod languages;
+mod modules;
+mod pch;
+mod unity;
+mod cache;
+mod toolchain;
+mod debuginfo;
+mod checks;
+
+pub use exports::{ExportSymbols, UnexpectedExports};
+pub use bench::{BenchResult, BenchesFailed};
+pub use generators::Generator;
+pub use languages::{Language, Languages, UnknownLanguage, UnknownLanguageKey};
+pub use modules::ModuleError;
+pub use cache::CompileCache;
+pub use toolchain::{CompilerFamily, CompilerInfo, Lto, Toolchain, UnsupportedCompiler, UnsupportedLto};
+pub use debuginfo::{DebuginfoLayout, Strip};
+pub use checks::{Check, InvalidCheck};
+use languages::{AddCompileOptionKeys, CompileOptionKeys, CompilerKeys, PerLanguage};
+pub use test_runner::{DuplicateUnitTest, TestFormat, TestOptions, TestOutcome, TestReport, TestResult, UnknownTestFormat};
+pub use instrumentation::{Instrumentation, Sanitizer, UnknownSanitizer, IncompatibleSanitizers, FileCoverage, clear_coverage_data, collect_coverage};
+
+/// Header providing `GOCAR_TEST` macro for unit tests inside of library sources
+const GOCAR_TEST_HEADER: &str = include_str!("../include/gocar_test.h");
+/// Header providing timing API for benchmarks
+const GOCAR_BENCH_HEADER: &str = include_str!("../include/gocar_bench.h");
 
 #[derive(Debug)]
 pub struct FsError {
@@ -27,11 +60,124 @@ impl fmt::Display for FsError {
 
 type FsResult<T> = Result<T, FsError>;
 
+#[derive(Debug)]
+pub enum ProfileError {
+    UnknownParent { profile: String, parent: String, },
+    InheritanceCycle(Vec<String>),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProfileError::UnknownParent { profile, parent } => write!(f, "profile {} inherits from unknown profile {}", profile, parent),
+            ProfileError::InheritanceCycle(chain) => write!(f, "profile inheritance cycle: {}", chain.join(" -> ")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Filesystem(FsError),
     InvalidProfileName,
     Command(CommandError),
+    Profile(ProfileError),
+    LinkageDisallowed { library: PathBuf, linkage: Linkage, },
+    UnexpectedExports(UnexpectedExports),
+    UnknownExample(String),
+    AmbiguousUnit(AmbiguousUnit),
+    Module(ModuleError),
+    UnsupportedLto(UnsupportedLto),
+    UnsupportedCompiler(UnsupportedCompiler),
+    InvalidCheck(InvalidCheck),
+    DuplicateUnitTest(DuplicateUnitTest),
+    BenchesFailed(BenchesFailed),
+    UnknownLanguage(UnknownLanguage),
+    UnknownLanguageKey(UnknownLanguageKey),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Filesystem(error) => fmt::Display::fmt(error, f),
+            Error::InvalidProfileName => write!(f, "invalid profile name"),
+            Error::Command(error) => fmt::Display::fmt(error, f),
+            Error::Profile(error) => fmt::Display::fmt(error, f),
+            Error::LinkageDisallowed { library, linkage: Linkage::Static } => write!(f, "library {} disallows static linkage", library.display()),
+            Error::LinkageDisallowed { library, linkage: Linkage::Dynamic } => write!(f, "library {} disallows dynamic linkage", library.display()),
+            Error::LinkageDisallowed { library, linkage: Linkage::Both } => write!(f, "library {} disallows both static and dynamic linkage", library.display()),
+            Error::UnexpectedExports(error) => fmt::Display::fmt(error, f),
+            Error::UnknownExample(name) => write!(f, "no example named {} found in examples directory", name),
+            Error::AmbiguousUnit(error) => fmt::Display::fmt(error, f),
+            Error::Module(error) => fmt::Display::fmt(error, f),
+            Error::UnsupportedLto(error) => fmt::Display::fmt(error, f),
+            Error::UnsupportedCompiler(error) => fmt::Display::fmt(error, f),
+            Error::InvalidCheck(error) => fmt::Display::fmt(error, f),
+            Error::DuplicateUnitTest(error) => fmt::Display::fmt(error, f),
+            Error::BenchesFailed(error) => fmt::Display::fmt(error, f),
+            Error::UnknownLanguage(error) => fmt::Display::fmt(error, f),
+            Error::UnknownLanguageKey(error) => fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+impl From<ProfileError> for Error {
+    fn from(value: ProfileError) -> Self {
+        Error::Profile(value)
+    }
+}
+
+impl From<AmbiguousUnit> for Error {
+    fn from(value: AmbiguousUnit) -> Self {
+        Error::AmbiguousUnit(value)
+    }
+}
+
+impl From<ModuleError> for Error {
+    fn from(value: ModuleError) -> Self {
+        Error::Module(value)
+    }
+}
+
+impl From<UnsupportedLto> for Error {
+    fn from(value: UnsupportedLto) -> Self {
+        Error::UnsupportedLto(value)
+    }
+}
+
+impl From<UnsupportedCompiler> for Error {
+    fn from(value: UnsupportedCompiler) -> Self {
+        Error::UnsupportedCompiler(value)
+    }
+}
+
+impl From<InvalidCheck> for Error {
+    fn from(value: InvalidCheck) -> Self {
+        Error::InvalidCheck(value)
+    }
+}
+
+impl From<UnknownLanguage> for Error {
+    fn from(value: UnknownLanguage) -> Self {
+        Error::UnknownLanguage(value)
+    }
+}
+
+impl From<UnknownLanguageKey> for Error {
+    fn from(value: UnknownLanguageKey) -> Self {
+        Error::UnknownLanguageKey(value)
+    }
+}
+
+impl From<BenchesFailed> for Error {
+    fn from(value: BenchesFailed) -> Self {
+        Error::BenchesFailed(value)
+    }
+}
+
+impl From<DuplicateUnitTest> for Error {
+    fn from(value: DuplicateUnitTest) -> Self {
+        Error::DuplicateUnitTest(value)
+    }
 }
 
 impl From<FsError> for Error {
@@ -108,7 +254,10 @@ impl fmt::Debug for CommandError {
 struct Command {
     cmd: PathBuf,
     args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    current_dir: Option<PathBuf>,
     piped_stdout: bool,
+    null_stderr: bool,
 }
 
 impl Command {
@@ -116,7 +265,10 @@ impl Command {
         Command {
             cmd: path.into(),
             args: Vec::new(),
+            envs: Vec::new(),
+            current_dir: None,
             piped_stdout: false,
+            null_stderr: false,
         }
     }
 
@@ -130,20 +282,62 @@ impl Command {
         self
     }
 
+    fn env<K: Into<OsString>, V: Into<OsString>>(mut self, key: K, value: V) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    fn current_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
     fn piped_stdout(mut self) -> Self {
         self.piped_stdout = true;
         self
     }
 
+    /// Discards error output, for commands which are expected to fail
+    fn null_stderr(mut self) -> Self {
+        self.null_stderr = true;
+        self
+    }
+
+    /// Runs the command to completion and returns its standard output.
+    fn output(self) -> Result<Vec<u8>, CommandError> {
+        let (output, status) = self.output_with_status()?;
+        status.failure_into_error()?;
+        Ok(output)
+    }
+
+    /// Runs the command to completion and returns its standard output even if it failed.
+    fn output_with_status(self) -> Result<(Vec<u8>, ExitStatus), CommandError> {
+        let mut child = self.piped_stdout().spawn()?;
+        let mut output = Vec::new();
+        if let Err(error) = io::Read::read_to_end(child.child.stdout.as_mut().expect("Stdout not set"), &mut output) {
+            return Err(CommandError::Communication(CmdOperationError { command: child.command, error, }));
+        }
+
+        Ok((output, child.wait()?))
+    }
+
     fn spawn(self) -> Result<Child, CommandError> {
         let mut command = std::process::Command::new(&self.cmd);
 
         command.args(&self.args);
+        command.envs(self.envs.iter().map(|(key, value)| (key, value)));
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
 
         if self.piped_stdout {
             command.stdout(std::process::Stdio::piped());
         }
 
+        if self.null_stderr {
+            command.stderr(std::process::Stdio::null());
+        }
+
         match command.spawn() {
             Ok(child) => Ok(Child { command: self, child, }),
             Err(error) => Err(CommandError::Spawn(CmdOperationError { command: self, error, })),
@@ -208,6 +402,30 @@ fn copy_file<S: AsRef<Path> + Into<PathBuf>, D: AsRef<Path>>(source: S, dest: D)
     std::fs::copy(&source, dest).err_ctx(|| (source.into(), "copy file"))
 }
 
+/// Removes the file unless it doesn't exist.
+fn remove_file<P: AsRef<Path> + Into<PathBuf>>(path: P) -> FsResult<()> {
+    match std::fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).err_ctx(|| (path.into(), "remove file")),
+    }
+}
+
+/// Creates symlink `link` pointing to `target`, replacing existing file.
+fn symlink<T: AsRef<Path>, L: AsRef<Path> + Into<PathBuf>>(target: T, link: L) -> FsResult<()> {
+    remove_file(link.as_ref())?;
+    std::os::unix::fs::symlink(target, &link).err_ctx(|| (link.into(), "create symlink"))
+}
+
+/// Writes `content` to `path` unless the file already has it, so it doesn't trigger recompilation.
+fn write_if_changed<P: AsRef<Path> + Into<PathBuf>>(path: P, content: &[u8]) -> FsResult<()> {
+    if std::fs::read(&path).ok().as_deref() != Some(content) {
+        std::fs::write(&path, content).err_ctx(|| (path.into(), "write file"))?;
+    }
+
+    Ok(())
+}
+
 fn create_dir_all<P: AsRef<Path> + Into<PathBuf>>(path: P) -> FsResult<()> {
     std::fs::create_dir_all(&path).err_ctx(|| (path.into(), "create directory structure"))
 }
@@ -226,82 +444,102 @@ fn canonicalize_custom_wd<P: AsRef<Path> + Into<PathBuf>, WD: AsRef<Path>>(path:
 }
 
 fn include_option<P: AsRef<OsStr>>(dir: P) -> OsString {
-    let mut res = OsString::from("-I");
-    res.push(dir.as_ref());
+    prefixed_option("-I", dir)
+}
+
+/// Creates option like `-lfoo` from prefix and value
+fn prefixed_option<V: AsRef<OsStr>>(prefix: &str, value: V) -> OsString {
+    let mut res = OsString::from(prefix);
+    res.push(value.as_ref());
     res
 }
 
-struct HeaderExtractor<R: BufRead> {
-    reader: std::iter::Filter<std::iter::Map<io::Split<R>, fn(io::Result<Vec<u8>>) -> io::Result<Vec<u8>>>, fn(&io::Result<Vec<u8>>) -> bool>,
+type DepfileWord = io::Result<Vec<u8>>;
+type DepfileWords<R> = std::iter::Map<io::Split<R>, fn(DepfileWord) -> DepfileWord>;
+
+struct HeaderExtractor<'a, R: BufRead> {
+    reader: DepfileWords<R>,
+    header_extensions: &'a [String],
 }
 
 fn drop_lf(item: io::Result<Vec<u8>>) -> io::Result<Vec<u8>> {
     item.map(|mut item| { if item.last() == Some(&b'\n') { item.pop(); } item })
 }
 
-fn filter_headers(item: &io::Result<Vec<u8>>) -> bool {
-    match *item {
-        Ok(ref item) => item.ends_with(b".h") || item.ends_with(b".hpp"),
-        Err(_) => true,
-    }
-}
-
-impl<R: BufRead> HeaderExtractor<R> {
-    pub fn new(reader: R) -> Self {
+impl<'a, R: BufRead> HeaderExtractor<'a, R> {
+    pub fn new(reader: R, header_extensions: &'a [String]) -> Self {
         HeaderExtractor {
             reader: reader
                 .split(b' ')
-                .map(drop_lf as fn(io::Result<Vec<u8>>) -> io::Result<Vec<u8>>)
-                .filter(filter_headers as fn(&io::Result<Vec<u8>>) -> bool)
+                .map(drop_lf as fn(io::Result<Vec<u8>>) -> io::Result<Vec<u8>>),
+            header_extensions,
         }
     }
 }
 
-impl<R: BufRead> Iterator for HeaderExtractor<R> {
+impl<'a, R: BufRead> Iterator for HeaderExtractor<'a, R> {
     type Item = io::Result<PathBuf>;
 
     fn next(&mut self) -> Option<Self::Item> {
         use std::os::unix::ffi::OsStringExt;
 
-        self.reader.next().map(|item| item.map(std::ffi::OsString::from_vec).map(Into::into))
+        loop {
+            match self.reader.next()? {
+                Ok(word) => {
+                    let path = PathBuf::from(std::ffi::OsString::from_vec(word));
+                    if is_header(&path, self.header_extensions) {
+                        return Some(Ok(path));
+                    }
+                },
+                Err(error) => return Some(Err(error)),
+            }
+        }
     }
 }
 
-fn header_to_unit<'a, P: AsRef<Path> + Into<PathBuf>, I: 'a + IntoIterator<Item=&'a DetachedHeaders>>(path: P, mappings: I) -> Option<PathBuf> {
-    let mut path = path.into();
-    path.set_extension("c");
+/// Checks whether the file has one of the header extensions.
+fn is_header(path: &Path, header_extensions: &[String]) -> bool {
+    path.extension().map_or(false, |extension| header_extensions.iter().any(|known| extension == &**known))
+}
 
-    let c_exists = path.exists();
-    path.set_extension("cpp");
-    if path.exists() {
-        if c_exists {
-            None
-        } else {
-            Some(path)
+/// The header has more than one implementation, e.g. both `foo.c` and `foo.cpp`
+#[derive(Debug)]
+pub struct AmbiguousUnit {
+    pub header: PathBuf,
+    pub units: Vec<PathBuf>,
+}
+
+impl fmt::Display for AmbiguousUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "header {} is implemented by multiple units:", self.header.display())?;
+        for unit in &self.units {
+            write!(f, " {}", unit.display())?;
         }
-    } else {
-        if c_exists {
-            path.set_extension("c");
-            Some(path)
-        } else {
-            for mapping in mappings {
-                if let Ok(stripped) = path.strip_prefix(&mapping.includes) {
-                    let mut path = mapping.sources.join(stripped);
-                    return if path.exists() {
-                        Some(path)
-                    } else {
-                        path.set_extension("c");
-                        if path.exists() {
-                            Some(path)
-                        } else {
-                            None
-                        }
-                    };
-                }
-            }
-            None
+        Ok(())
+    }
+}
+
+/// Finds the unit implementing the header, written in any of the known languages.
+///
+/// Units next to the header are tried first, then units in sources of detached headers.
+/// Returns `None` if there's no unit and an error if the header has more than one
+/// implementation.
+fn header_to_unit<'a, P: AsRef<Path> + Into<PathBuf>, I: 'a + IntoIterator<Item=&'a DetachedHeaders>>(path: P, languages: &Languages, mappings: I) -> Result<Option<PathBuf>, AmbiguousUnit> {
+    let header = path.into();
+    let find_units = |path: &Path| languages.extensions().map(|extension| path.with_extension(extension)).filter(|unit| unit.exists()).collect::<Vec<_>>();
+
+    let mut units = find_units(&header);
+    if units.is_empty() {
+        if let Some(stripped) = mappings.into_iter().find_map(|mapping| Some((mapping, header.strip_prefix(&mapping.includes).ok()?))) {
+            units = find_units(&stripped.0.sources.join(stripped.1));
         }
     }
+
+    match units.len() {
+        0 => Ok(None),
+        1 => Ok(units.pop()),
+        _ => Err(AmbiguousUnit { header, units, }),
+    }
 }
 
 /// Convert path to a .c(pp) file to a path to .o file.
@@ -313,21 +551,28 @@ fn unit_to_obj<P: AsRef<Path> + Into<PathBuf>>(path: P) -> Option<PathBuf> {
 }
 
 fn get_headers<P: AsRef<Path> + Into<PathBuf>>(file: P, env: &BuildEnv) -> GocarResult<Vec<PathBuf>> {
-    let compiler = Compiler::determine_from_file(&file).expect("Unknown extension");
-    let options = env.profile.compile_options.all(compiler);
-    let compiler = env.profile.compiler(compiler);
+    let language = env.project.languages.determine_from_file(&file).expect("Unknown extension");
+    if language.scan_options.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let options = env.profile.compile_options.all(language);
+    let compiler = env.profile.compiler(language);
+    let family = env.toolchain.detect(&compiler)?.family;
 
     let mut cpp = Command::new(compiler)
         .args(env.include_dirs)
+        .args(&language.required_options)
         .args(options.clone())
-        .arg("-MM")
+        .args(compiler_options(env, language)?)
+        .args(&*family.scan_options(&language.scan_options))
         .arg(file.as_ref())
         .piped_stdout()
         .spawn()?;
 
-    let headers = HeaderExtractor::new(io::BufReader::new(cpp.child.stdout.take().expect("Stdout not set")));
-    let headers = match headers.collect::<Result<_, _>>() {
-        Ok(headers) => Ok(headers),
+    let headers = HeaderExtractor::new(io::BufReader::new(cpp.child.stdout.take().expect("Stdout not set")), &env.project.header_extensions);
+    let mut headers = match headers.collect::<Result<Vec<_>, _>>() {
+        Ok(headers) => headers,
         Err(error) => return Err(CommandError::Communication(CmdOperationError {
             command: cpp.command,
             error,
@@ -335,11 +580,23 @@ fn get_headers<P: AsRef<Path> + Into<PathBuf>>(file: P, env: &BuildEnv) -> Gocar
     };
 
     cpp.wait()?.failure_into_error()?;
-    headers
+
+    // tcc lists system headers too, unlike `-MM`
+    if family == CompilerFamily::Tcc {
+        headers.retain(|header| header.is_relative() || header.starts_with(env.project_dir));
+    }
+    Ok(headers)
+}
+
+/// Options depending on the compiler of the language: defines identifying it and profile options
+/// for its family
+fn compiler_options(env: &BuildEnv, language: &Language) -> GocarResult<Vec<PathBuf>> {
+    let compiler = env.toolchain.detect(&env.profile.compiler(language))?;
+    Ok(compiler.defines().iter().cloned().chain(env.profile.family_compile_options(compiler.family, language).cloned()).collect())
 }
 
-fn estimate_header_only<P: AsRef<Path>>(path: P, env: &BuildEnv, compiler: Compiler) -> Result<bool, CommandError> {
-    let options = env.profile.compile_options.all(compiler);
+fn estimate_header_only<P: AsRef<Path>>(path: P, env: &BuildEnv, language: &Language) -> Result<bool, CommandError> {
+    let options = env.profile.compile_options.all(language);
 
     Ok(Command::new("is_header_only.py")
         .arg(path.as_ref())
@@ -357,7 +614,7 @@ fn scan_c_files<P: AsRef<Path> + Into<PathBuf>, I: IntoIterator<Item=P>>(root_fi
         let file = canonicalize_custom_wd(file, env.project_dir)?;
 
         println!("\u{1B}[32;1m    Scanning\u{1B}[0m {:?}", file.strip_prefix(env.project_dir).unwrap_or(&file));
-        get_headers(&file, env).map(|headers| (file, headers)).map_err(Into::into)
+        get_headers(&file, env).map(|headers| (file, headers))
     }).collect::<Result<HashMap<_, _>, _>>()?;
 
     let mut prev_file_count = 0;
@@ -365,17 +622,20 @@ fn scan_c_files<P: AsRef<Path> + Into<PathBuf>, I: IntoIterator<Item=P>>(root_fi
     while prev_file_count != scanned_files.len() {
         prev_file_count = scanned_files.len();
         let candidates = scanned_files
-            .iter()
-            .flat_map(|(_, headers)| headers.iter())
-            .filter_map(|header| {
+            .values()
+            .flat_map(|headers| headers.iter())
+            .map(|header| {
                 let canonicalized = canonicalize_custom_wd(header, env.project_dir).unwrap();
                 if env.headers_only.contains(&canonicalized) {
-                    None
+                    Ok(None)
                 } else {
-                    let unit = header_to_unit(canonicalized, &detached_headers);
+                    let unit = header_to_unit(canonicalized, &env.project.languages, &detached_headers)?;
                     if !env.project.ignore_missing_sources && unit.is_none() {
                         let is_ok = if env.project.estimate_headers_only {
-                            match estimate_header_only(header, env, Compiler::Cpp) {
+                            let estimate = env.project.languages.get("cpp")
+                                .map_err(Error::from)
+                                .and_then(|cpp| estimate_header_only(header, env, cpp).map_err(Into::into));
+                            match estimate {
                                 Ok(val) => val,
                                 Err(err) => {
                                     eprintln!("Error: failed to estimate whether {} is header-only: {}", header.display(), err);
@@ -390,9 +650,12 @@ fn scan_c_files<P: AsRef<Path> + Into<PathBuf>, I: IntoIterator<Item=P>>(root_fi
                             panic!("Missing source for header {}", header.display())
                         }
                     }
-                    unit
+                    Ok(unit)
                 }
             })
+            .collect::<Result<Vec<_>, AmbiguousUnit>>()?
+            .into_iter()
+            .flatten()
             .filter(|file| !scanned_files.contains_key(file))
             .filter(|file| !ignore_files.contains(file))
             .collect::<Vec<_>>();
@@ -466,53 +729,43 @@ impl<'a> Iterator for ModifiedSources<'a> {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
-pub enum Compiler {
-    C,
-    Cpp,
+/// Compile options common to all languages and specific to some of them
+///
+/// Configured as `compile_options` and `<key>_compile_options`, where `<key>` is the options key
+/// of the language, e.g. `c_compile_options`.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(from = "PerLanguage<CompileOptionKeys, Vec<PathBuf>>")]
+pub struct CompileOptions {
+    pub common: Vec<PathBuf>,
+    /// Options specific to languages, by their options key
+    pub languages: HashMap<String, Vec<PathBuf>>,
 }
 
-impl Compiler {
-    pub fn determine_from_file<P: AsRef<Path>>(file: P) -> Option<Self> {
-        let ext = file.as_ref().extension()?;
-        // Why not "C" as well? According to https://stackoverflow.com/a/1546107 it means C++ but I
-        // find it highly confusing. I'm not supporting it until there's a big pressure.
-        if ext == "c" {
-            Some(Compiler::C)
-        } else if ext == "cpp" || ext == "cc" || ext == "cxx" || ext == "CPP" || ext == "CC" || ext == "CXX" {
-            Some(Compiler::Cpp)
-        } else {
-            None
+impl<K> From<PerLanguage<K, Vec<PathBuf>>> for CompileOptions {
+    fn from(value: PerLanguage<K, Vec<PathBuf>>) -> Self {
+        CompileOptions {
+            common: value.common.unwrap_or_default(),
+            languages: value.languages,
         }
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
-pub struct CompileOptions {
-    #[serde(rename = "compile_options")]
-    #[serde(default)]
-    pub common: Vec<PathBuf>,
-    #[serde(rename = "c_compile_options")]
-    #[serde(default)]
-    pub c: Vec<PathBuf>,
-    #[serde(rename = "cpp_compile_options")]
-    #[serde(default)]
-    pub cpp: Vec<PathBuf>,
-}
-
 impl CompileOptions {
-    pub fn all(&self, compiler: Compiler) -> impl Iterator<Item=&PathBuf> + Clone {
-        self.common.iter().chain(match compiler {
-            Compiler::C => &self.c,
-            Compiler::Cpp => &self.cpp,
-        })
+    pub fn all<'a>(&'a self, language: &Language) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        self.common.iter().chain(self.languages.get(language.options_key()).into_iter().flatten())
+    }
+
+    fn extend(&mut self, other: CompileOptions) {
+        self.common.extend(other.common);
+        for (language, options) in other.languages {
+            self.languages.entry(language).or_default().extend(options);
+        }
     }
 
     fn only_common(options: Vec<PathBuf>) -> Self {
         CompileOptions {
             common: options,
-            c: Vec::new(),
-            cpp: Vec::new(),
+            languages: HashMap::new(),
         }
     }
 
@@ -525,7 +778,7 @@ impl CompileOptions {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct OsSpec {
     bin_spec: TargetSpec,
     static_lib_spec: TargetSpec,
@@ -537,21 +790,20 @@ impl OsSpec {
         OsSpec {
             bin_spec: TargetSpec {
                 extension: "".into(),
+                object_dir: None,
                 required_compile_options: Default::default(),
                 required_link_options: Default::default(),
             },
             static_lib_spec: TargetSpec {
                 extension: "a".into(),
+                object_dir: None,
                 required_compile_options: Default::default(),
                 required_link_options: vec![],
             },
             dynamic_lib_spec: TargetSpec {
                 extension: "so".into(),
-                required_compile_options: CompileOptions {
-                    common: vec!["-fPIC".into()],
-                    c: Default::default(),
-                    cpp: Default::default(),
-                },
+                object_dir: Some("pic".into()),
+                required_compile_options: CompileOptions::only_common(vec!["-fPIC".into()]),
                 required_link_options: vec!["-shared".into()],
             },
         }
@@ -570,11 +822,23 @@ pub struct BuildEnv<'a> {
     pub profile: &'a Profile,
     pub project: &'a Project,
     pub headers_only: &'a HashSet<PathBuf>,
+    /// Canonical paths of units generated by generators, which are roots of every target
+    pub generated_units: &'a [PathBuf],
+    /// BMIs of C++20 modules provided by linked libraries of the project, by names of modules
+    pub linked_modules: &'a BTreeMap<String, PathBuf>,
+    pub compile_cache: Option<&'a CompileCache>,
+    pub toolchain: &'a Toolchain,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct TargetSpec {
     extension: OsString,
+    /// Subdirectory of the target directory for objects
+    ///
+    /// Needed when objects are compiled with different required options, so that they don't
+    /// overwrite each other.
+    #[serde(default)]
+    object_dir: Option<OsString>,
     required_compile_options: CompileOptions,
     required_link_options: Vec<PathBuf>,
 }
@@ -596,12 +860,52 @@ impl TargetKind for BinTarget {
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
 pub enum LibraryType {
     Static,
     Dynamic,
 }
 
+/// Linkage of libraries requested for the build
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+pub enum Linkage {
+    Static,
+    Dynamic,
+    Both,
+}
+
+impl Linkage {
+    pub fn library_types(self) -> &'static [LibraryType] {
+        match self {
+            Linkage::Static => &[LibraryType::Static],
+            Linkage::Dynamic => &[LibraryType::Dynamic],
+            Linkage::Both => &[LibraryType::Static, LibraryType::Dynamic],
+        }
+    }
+}
+
+impl std::str::FromStr for Linkage {
+    type Err = UnknownLinkage;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "static" => Ok(Linkage::Static),
+            "dynamic" => Ok(Linkage::Dynamic),
+            "both" => Ok(Linkage::Both),
+            _ => Err(UnknownLinkage(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownLinkage(String);
+
+impl fmt::Display for UnknownLinkage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown linkage {}, expected one of static, dynamic, both", self.0)
+    }
+}
+
 #[derive(Debug)]
 pub enum LibTarget {}
 
@@ -617,9 +921,12 @@ impl TargetKind for LibTarget {
 }
 
 struct CompileOutput {
-    files: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Canonical paths of all compiled units
+    sources: Vec<PathBuf>,
+    objects: Vec<PathBuf>,
     up_to_date: bool,
-    has_cpp: bool,
+    /// Name of the language whose compiler links the objects
+    linker: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -630,54 +937,150 @@ pub struct Target<K: TargetKind> {
     pub compile_options: CompileOptions,
     #[serde(default)]
     pub link_options: Vec<PathBuf>,
+    /// Options passed to `ar` when the target is archived into a static library
+    #[serde(default)]
+    pub archive_options: Vec<PathBuf>,
     #[serde(default)]
     pub ignore_files: HashSet<PathBuf>,
+    /// Header precompiled and included into every unit of the target
+    #[serde(default)]
+    pub precompiled_header: Option<PathBuf>,
+    /// Compiles generated files including batches of units instead of individual units
+    #[serde(default)]
+    pub unity_build: bool,
+    /// Maximum number of units in a single unity file, unlimited by default
+    #[serde(default)]
+    pub unity_batch_size: Option<usize>,
+    /// Units compiled separately in unity builds
+    #[serde(default)]
+    pub unity_exclude: HashSet<PathBuf>,
     #[serde(skip)]
     pub _phantom: std::marker::PhantomData<K>,
 }
 
 impl<K: TargetKind> Target<K> {
-    fn compile(&self, env: &BuildEnv, skip_older: Option<SystemTime>, spec: &TargetSpec) -> GocarResult<CompileOutput> {
-        let ignore_files = self.ignore_files.iter().map(canonicalize).collect::<Result<_, _>>()?;
-        let files = scan_c_files(&self.root_files, &ignore_files, env)?;
+    /// File mapping names of C++20 modules provided by units of the target to their BMIs
+    fn modules_mapper(&self, env: &BuildEnv, spec: &TargetSpec) -> PathBuf {
+        let mut mapper = match &spec.object_dir {
+            Some(dir) => env.target_dir.join(dir),
+            None => env.target_dir.to_owned(),
+        }.join(&self.name).into_os_string();
+        mapper.push(".modules");
+        mapper.into()
+    }
+
+    fn compile(&self, env: &BuildEnv, skip_older: Option<SystemTime>, spec: &TargetSpec, extra_options: &[PathBuf]) -> GocarResult<CompileOutput> {
+        let ignore_files = self.ignore_files.iter().map(canonicalize).collect::<Result<HashSet<_>, _>>()?;
+        let generated_units = env.generated_units.iter().filter(|unit| !ignore_files.contains(*unit)).cloned();
+        let mut files = scan_c_files(self.root_files.iter().cloned().chain(generated_units), &ignore_files, env)?;
+        let obj_dir = match &spec.object_dir {
+            Some(dir) => Cow::Owned(env.target_dir.join(dir)),
+            None => Cow::Borrowed(env.target_dir),
+        };
+        let include_param: PathBuf = include_option(env.include_dir).into();
+
+        let modules = if env.project.modules {
+            let mapper = self.modules_mapper(env, spec);
+            let options = |language: &Language| Ok(env.include_dirs
+                .iter()
+                .cloned()
+                .chain(self.unit_compile_options(env, spec, extra_options, &include_param, language).map(Into::into))
+                .chain(compiler_options(env, language)?.into_iter().map(Into::into))
+                .collect());
+            Some(modules::Modules::resolve(&mut files, &ignore_files, &obj_dir, mapper, env, options)?)
+        } else {
+            None
+        };
+
+        let precompiled_headers = self.precompile_header(&files, env, &obj_dir, spec, extra_options, &include_param)?;
+        for (unit, dependencies) in files.iter_mut() {
+            let language = env.project.languages.determine_from_file(unit).expect("Unknown extension");
+            if let Some(precompiled) = precompiled_headers.get(&language.name) {
+                dependencies.push(precompiled.output.clone());
+            }
+        }
+
+        let sources = files.keys().cloned().collect::<Vec<_>>();
+        if self.unity_build {
+            let exclude = self.unity_exclude.iter().map(|path| canonicalize_custom_wd(path, env.project_dir)).collect::<FsResult<HashSet<_>>>()?;
+            let out_dir = obj_dir.join("unity").join(&self.name);
+            let uses_modules = |unit: &Path| modules.as_ref().map_or(false, |modules| modules.uses_modules(unit));
+            files = unity::combine(files, &env.project.languages, self.unity_batch_size, &out_dir, |unit| exclude.contains(unit) || uses_modules(unit))?;
+        }
+
+        let mut modified = ModifiedSources::scan(skip_older, &files).collect::<FsResult<Vec<_>>>()?;
+        if let Some(modules) = &modules {
+            modules.sort(&mut modified);
+        }
 
         let mut up_to_date = true;
-        let mut has_cpp = false;
-        for path in ModifiedSources::scan(skip_older, &files) {
-            let path = path?;
+        for path in modified {
             up_to_date = false;
 
-            let output = objs::get_obj_path(&env.target_dir, &env.project_dir, unit_to_obj(path).unwrap());
+            let output = objs::get_obj_path(&obj_dir, env.project_dir, unit_to_obj(path).unwrap());
             create_dir_all(output.parent().unwrap())?;
-            println!("   \u{1B}[32;1mCompiling\u{1B}[0m {:?}", output.strip_prefix(&env.strip_prefix).unwrap_or(&output));
-            let compiler = Compiler::determine_from_file(&path).expect("Unknown extension");
-            has_cpp |= compiler == Compiler::Cpp;
-            let include_param: PathBuf = include_option(env.include_dir).into();
-            let compile_options = spec.required_compile_options
-                .all(compiler)
-                .chain(env.profile.compile_options.all(compiler))
-                .chain(self.compile_options.all(compiler))
-                .chain(std::iter::once(&include_param));
-
-            let compiler = env.profile.compiler(compiler);
-
-            Command::new(compiler)
-                .args(env.include_dirs)
-                .args(compile_options.clone())
-                .arg("-c")
-                .arg("-o")
-                .arg(&output)
-                .arg(path)
-                .spawn()?
-                .wait()?
-                .failure_into_error()?;
+            println!("   \u{1B}[32;1mCompiling\u{1B}[0m {:?}", output.strip_prefix(env.strip_prefix).unwrap_or(&output));
+            let language = env.project.languages.determine_from_file(path).expect("Unknown extension");
+            let module_options = modules.as_ref().map(|modules| modules.options(path, language)).unwrap_or_default();
+            let pch_options = precompiled_headers.get(&language.name).map(|precompiled| precompiled.options());
+            let compiler = env.profile.compiler(language);
+            let lto_options = env.toolchain.lto_options(&compiler, env.profile.lto.unwrap_or_default())?;
+            let compiler_options = compiler_options(env, language)?;
+            let compile_options = self.unit_compile_options(env, spec, extra_options, &include_param, language)
+                .chain(&compiler_options)
+                .chain(&module_options)
+                .chain(pch_options.iter().flatten())
+                .chain(&lto_options);
+
+            // Units producing module interfaces have outputs besides the object, which aren't cached
+            let cached = match env.compile_cache {
+                Some(cache) if module_options.is_empty() => {
+                    // The precompiled header is replaced by its source, which is what the key should depend on
+                    let pch_source_options = precompiled_headers.get(&language.name).map(|precompiled| precompiled.source_options());
+                    let key_options = self.unit_compile_options(env, spec, extra_options, &include_param, language)
+                        .chain(&compiler_options)
+                        .chain(pch_source_options.iter().flatten())
+                        .chain(&lto_options)
+                        .map(|option| option.as_os_str().to_owned());
+                    let options = env.include_dirs.iter().cloned().chain(key_options).collect::<Vec<_>>();
+                    Some((cache, cache.key(&compiler, &options, &language.name, path)?))
+                },
+                _ => None,
+            };
+
+            let restored = match &cached {
+                Some((cache, key)) => cache.restore(key, &output)?,
+                None => false,
+            };
+
+            if !restored {
+                let command = match &env.profile.compiler_launcher {
+                    Some(launcher) => Command::new(launcher).arg(&*compiler),
+                    None => Command::new(&*compiler),
+                };
+
+                command
+                    .args(env.include_dirs)
+                    .args(compile_options.clone())
+                    .arg("-c")
+                    .arg("-o")
+                    .arg(&output)
+                    .arg(path)
+                    .spawn()?
+                    .wait()?
+                    .failure_into_error()?;
+
+                if let Some((cache, key)) = &cached {
+                    cache.store(key, &output)?;
+                }
+            }
 
             if let Some(post_compile) = &env.project.post_compile {
-                println!("\u{1B}[32;1mPost compile\u{1B}[0m {:?}", output.strip_prefix(&env.strip_prefix).unwrap_or(&output));
+                println!("\u{1B}[32;1mPost compile\u{1B}[0m {:?}", output.strip_prefix(env.strip_prefix).unwrap_or(&output));
                 Command::new(post_compile)
                     .arg(&output)
                     .arg(path)
-                    .arg(compiler)
+                    .arg(compiler.as_os_str())
                     .args(env.include_dirs)
                     .args(compile_options.clone())
                     .spawn()?
@@ -686,28 +1089,144 @@ impl<K: TargetKind> Target<K> {
             }
         }
 
+        let languages = &env.project.languages;
+        let linker = languages.linker(files.keys().filter_map(|file| languages.determine_from_file(file)))?.name.clone();
+        let objects = files.keys().map(|file| objs::get_obj_path(&obj_dir, env.project_dir, unit_to_obj(file).unwrap())).collect();
+
         Ok(CompileOutput {
-            files,
+            sources,
+            objects,
             up_to_date,
-            has_cpp,
+            linker,
         })
     }
+
+    /// Precompiles `precompiled_header` for every language of units which supports it, unless its compiler doesn't.
+    ///
+    /// Precompiled headers are stored in `pch/<target>/<language>` inside of the object directory,
+    /// so that each set of required options has its own. Returns precompiled headers by names of languages.
+    fn precompile_header(&self, files: &HashMap<PathBuf, Vec<PathBuf>>, env: &BuildEnv, obj_dir: &Path, spec: &TargetSpec, extra_options: &[PathBuf], include_param: &PathBuf) -> GocarResult<HashMap<String, pch::PrecompiledHeader>> {
+        let header = match &self.precompiled_header {
+            Some(header) => canonicalize_custom_wd(header, env.project_dir)?,
+            None => return Ok(HashMap::new()),
+        };
+
+        let mut languages = files
+            .keys()
+            .filter_map(|unit| env.project.languages.determine_from_file(unit))
+            .filter(|language| language.header_language.is_some())
+            .map(|language| &language.name)
+            .collect::<Vec<_>>();
+        languages.sort();
+        languages.dedup();
+
+        let mut precompiled_headers = HashMap::new();
+        for name in languages {
+            let language = env.project.languages.get(name)?;
+            let compiler = env.profile.compiler(language);
+            let family = env.toolchain.detect(&compiler)?.family;
+            if !family.supports_pch() {
+                println!("     \u{1B}[33;1mWarning\u{1B}[0m: {} doesn't support precompiled headers, compiling {} units without them", family, name);
+                continue;
+            }
+
+            let options = env.include_dirs
+                .iter()
+                .cloned()
+                .chain(self.unit_compile_options(env, spec, extra_options, include_param, language).map(Into::into))
+                .chain(compiler_options(env, language)?.into_iter().map(Into::into))
+                .collect();
+            let out_dir = obj_dir.join("pch").join(&self.name).join(name);
+            let precompiled = pch::build(&header, language, &compiler, options, &out_dir, &env.project.header_extensions, env.strip_prefix)?;
+            precompiled_headers.insert(name.clone(), precompiled);
+        }
+
+        Ok(precompiled_headers)
+    }
+
+    /// Options passed to the compiler when compiling a unit of the target written in `language`
+    fn unit_compile_options<'a>(&'a self, env: &BuildEnv<'a>, spec: &'a TargetSpec, extra_options: &'a [PathBuf], include_param: &'a PathBuf, language: &'a Language) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        language.required_options
+            .iter()
+            .chain(spec.required_compile_options.all(language))
+            .chain(env.profile.compile_options.all(language))
+            .chain(self.compile_options.all(language))
+            .chain(env.profile.target_compile_options(&self.name, language))
+            .chain(extra_options)
+            .chain(std::iter::once(include_param))
+    }
+
+    /// Options passed to the compiler when linking the target
+    ///
+    /// Options required by the target kind go first, followed by profile options, per-target
+    /// profile overrides and options of the target itself.
+    fn link_options<'a>(&'a self, profile: &'a Profile, spec: &'a TargetSpec) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        spec.required_link_options
+            .iter()
+            .chain(&profile.link_options)
+            .chain(profile.target_link_options(&self.name))
+            .chain(&self.link_options)
+    }
+
+    /// Options passed to `ar` when archiving the target
+    fn archive_options<'a>(&'a self, profile: &'a Profile) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        profile.archive_options
+            .iter()
+            .chain(profile.target_archive_options(&self.name))
+            .chain(&self.archive_options)
+    }
+}
+
+/// Finds artifacts of libraries in `libs` the way the linker would
+///
+/// `-l<name>` is looked up in directories given by `-L` options, preferring the dynamic library
+/// in each of them. Libraries which aren't found there, such as system ones, are skipped.
+/// Other options which aren't flags are paths to the libraries.
+fn linked_libraries(lib_dirs: &[OsString], libs: &[OsString], os: &OsSpec) -> Vec<PathBuf> {
+    let dirs = lib_dirs
+        .iter()
+        .filter_map(|option| option.to_str()?.strip_prefix("-L"))
+        .collect::<Vec<_>>();
+
+    libs.iter()
+        .filter_map(|lib| match lib.to_str().and_then(|lib| lib.strip_prefix("-l")) {
+            Some(name) => dirs
+                .iter()
+                .flat_map(|dir| [&os.dynamic_lib_spec, &os.static_lib_spec].iter().map(move |spec| {
+                    let mut file_name = OsString::from(format!("lib{}.", name));
+                    file_name.push(&spec.extension);
+                    Path::new(dir).join(file_name)
+                }).collect::<Vec<_>>())
+                .find(|path| path.is_file()),
+            None if !lib.to_string_lossy().starts_with('-') => Some(PathBuf::from(lib)),
+            None => None,
+        })
+        .collect()
 }
 
-fn link_using_compiler<CP: AsRef<OsStr>, OP: AsRef<Path>, O: Into<OsString>, I: IntoIterator<Item=O>>(compiler: CP, output: OP, options: I, files: &HashMap<PathBuf, Vec<PathBuf>>, env: &BuildEnv) -> Result<(), CommandError> {
+/// Links the objects using the compiler, adding options for its family and options enabling LTO
+/// if the profile uses it
+fn link_using_compiler<CP: AsRef<Path>, OP: AsRef<Path>, O: Into<OsString>, I: IntoIterator<Item=O>>(compiler: CP, output: OP, options: I, objects: &[PathBuf], env: &BuildEnv) -> GocarResult<()> {
     let output = output.as_ref();
+    let family = env.toolchain.detect(compiler.as_ref())?.family;
+    let lto_options = env.toolchain.lto_options(compiler.as_ref(), env.profile.lto.unwrap_or_default())?;
 
-    println!("     \u{1B}[32;1mLinking\u{1B}[0m {:?}", output.strip_prefix(&env.strip_prefix).unwrap_or(&output));
-    Command::new(&compiler)
+    println!("     \u{1B}[32;1mLinking\u{1B}[0m {:?}", output.strip_prefix(env.strip_prefix).unwrap_or(output));
+    Command::new(compiler.as_ref())
+        .args(env.profile.linker_option(family))
+        .args(debuginfo::link_option(env))
         .args(options)
+        .args(env.profile.family_link_options(family))
+        .args(lto_options)
         .arg("-o")
-        .arg(&output)
-        .args(files.clone().into_iter().map(|(file, _)| objs::get_obj_path(&env.target_dir, &env.project_dir, unit_to_obj(file).unwrap())))
+        .arg(output)
+        .args(objects)
         .args(env.lib_dirs)
         .args(env.libs)
         .spawn()?
         .wait()?
-        .failure_into_error()
+        .failure_into_error()?;
+    Ok(())
 }
 
 #[derive(Debug, Deserialize)]
@@ -717,25 +1236,53 @@ pub struct Binary {
 }
 
 impl Binary {
-    pub fn build(&self, env: &BuildEnv) -> GocarResult<()> {
+    /// Program built from a single source file, such as a test, a benchmark or an example
+    ///
+    /// Units in `ignore_files` are expected to be linked from libraries.
+    fn program(name: String, source: PathBuf, ignore_files: HashSet<PathBuf>) -> Self {
+        Binary {
+            target: Target {
+                name: name.into(),
+                root_files: std::iter::once(source).collect(),
+                compile_options: Default::default(),
+                link_options: Vec::new(),
+                archive_options: Vec::new(),
+                ignore_files,
+                precompiled_header: None,
+                unity_build: false,
+                unity_batch_size: None,
+                unity_exclude: HashSet::new(),
+                _phantom: Default::default(),
+            }
+        }
+    }
+
+    /// Path to the built binary
+    pub fn output_path(&self, env: &BuildEnv) -> PathBuf {
         let mut bin_path = env.target_dir.join(&self.target.name);
         bin_path.set_extension(&env.os.bin_spec.extension);
+        bin_path
+    }
+
+    pub fn build(&self, env: &BuildEnv) -> GocarResult<()> {
+        let bin_path = self.output_path(env);
         let target_mtime = get_file_mtime(&bin_path)?;
-        let compiled = self.target.compile(env, target_mtime, &env.os.bin_spec)?;
+        let compiled = self.target.compile(env, target_mtime, &env.os.bin_spec, &[])?;
+        let libs_modified = match target_mtime {
+            Some(target_mtime) => is_older(target_mtime, linked_libraries(env.lib_dirs, env.libs, &env.os).iter())?,
+            None => true,
+        };
 
-        if compiled.up_to_date {
-            println!("  \u{1B}[32;1mUp to date\u{1B}[0m {:?}", bin_path.strip_prefix(&env.strip_prefix).unwrap_or(&bin_path));
+        if compiled.up_to_date && !libs_modified {
+            println!("  \u{1B}[32;1mUp to date\u{1B}[0m {:?}", bin_path.strip_prefix(env.strip_prefix).unwrap_or(&bin_path));
             return Ok(());
         }
 
-        let compiler = if compiled.has_cpp {
-            &env.profile.cpp_compiler
-        } else {
-            &env.profile.c_compiler
-        };
+        let compiler = env.profile.compiler(env.project.languages.get(&compiled.linker)?);
 
-        let link_options = env.os.bin_spec.required_link_options.iter().chain(&self.target.link_options);
-        link_using_compiler(compiler, bin_path, link_options, &compiled.files, env).map_err(Into::into)
+        let link_options = self.target.link_options(env.profile, &env.os.bin_spec);
+        link_using_compiler(compiler, &bin_path, link_options, &compiled.objects, env)?;
+        debuginfo::process(&bin_path, env)
     }
 }
 
@@ -749,112 +1296,567 @@ pub struct Library {
     pub disallow_dynamic: bool,
     #[serde(default)]
     pub public_headers: HashSet<PathBuf>,
+    /// Version of the library, overrides version of the project
+    #[serde(default)]
+    pub version: Option<Version>,
+    /// Linker version script controlling symbols exported from the dynamic library
+    #[serde(default)]
+    pub version_script: Option<PathBuf>,
+    /// When not `all`, symbols are hidden unless marked with `<NAME>_API` macro from generated
+    /// `<name>_export.h` and exports of the shared library are checked against public headers
+    #[serde(default)]
+    pub export_symbols: ExportSymbols,
 }
 
-impl Library {
-    pub fn build(&self, env: &BuildEnv, linkage: LibraryType) -> GocarResult<()> {
-        let mut lib_name = OsString::from("lib");
-        lib_name.push(&self.target.name);
-        let mut lib_path = env.target_dir.join(&lib_name);
-        let lib_spec = match linkage {
-            LibraryType::Dynamic => &env.os.dynamic_lib_spec,
-            LibraryType::Static => &env.os.static_lib_spec,
-        };
-        lib_path.set_extension(&lib_spec.extension);
-        let target_mtime = get_file_mtime(&lib_path)?;
+/// Version in the form `MAJOR[.MINOR[.PATCH]]`, missing components are zero
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
 
-        let compiled = self.target.compile(env, target_mtime, lib_spec)?;
+impl std::str::FromStr for Version {
+    type Err = InvalidVersion;
 
-        if compiled.up_to_date {
-            println!("  \u{1B}[32;1mUp to date\u{1B}[0m {:?}", lib_path.strip_prefix(&env.strip_prefix).unwrap_or(&lib_path));
-            return Ok(());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut components = s.split('.').map(|component| component.parse::<u64>().map_err(|_| InvalidVersion(s.to_owned())));
+        let major = components.next().ok_or_else(|| InvalidVersion(s.to_owned()))??;
+        let minor = components.next().transpose()?.unwrap_or(0);
+        let patch = components.next().transpose()?.unwrap_or(0);
+        if components.next().is_some() {
+            return Err(InvalidVersion(s.to_owned()));
         }
 
-        let compiler = if compiled.has_cpp {
-            &env.profile.cpp_compiler
-        } else {
-            &env.profile.c_compiler
-        };
-
-        let link_options = lib_spec.required_link_options.iter().chain(&self.target.link_options);
-
-        match linkage {
-            LibraryType::Dynamic => link_using_compiler(compiler, lib_path, link_options, &compiled.files, env),
-            LibraryType::Static => Library::link_static(lib_path, link_options, &compiled.files, env),
-        }
-        .map_err(Into::into)
+        Ok(Version { major, minor, patch, })
     }
+}
 
-    fn link_static<OP: AsRef<Path>, O: AsRef<OsStr>, I: IntoIterator<Item=O> + Clone>(output: OP, options: I, files: &HashMap<PathBuf, Vec<PathBuf>>, env: &BuildEnv) -> Result<(), CommandError> {
-        let output = output.as_ref();
-        let mut args: OsString = "crs".into();
-        for arg in options {
-            args.push(arg);
-        }
-
-        println!("     \u{1B}[32;1mLinking\u{1B}[0m {:?}", output.strip_prefix(&env.strip_prefix).unwrap_or(&output));
-        Command::new("ar")
-            .arg(&args)
-            .arg(&output)
-            .args(files.clone().into_iter().map(|(file, _)| objs::get_obj_path(&env.target_dir, &env.project_dir, unit_to_obj(file).unwrap())))
-            .spawn()?
-            .wait()?
-            .failure_into_error()
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
     }
 }
 
-fn default_c_compiler() -> PathBuf {
-	std::env::var_os("CC").map_or_else(|| "cc".to_owned().into(), Into::into)
+impl<'de> serde::Deserialize<'de> for Version {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        version.parse().map_err(serde::de::Error::custom)
+    }
 }
 
-fn default_cpp_compiler() -> PathBuf {
-	std::env::var_os("CXX").map_or_else(|| "c++".to_owned().into(), Into::into)
+#[derive(Debug)]
+pub struct InvalidVersion(String);
+
+impl fmt::Display for InvalidVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid version {}, expected MAJOR[.MINOR[.PATCH]]", self.0)
+    }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Profile {
-    #[serde(default = "default_c_compiler")]
-    pub c_compiler: PathBuf,
-    #[serde(default = "default_cpp_compiler")]
-    pub cpp_compiler: PathBuf,
-    #[serde(flatten)]
-    pub compile_options: CompileOptions,
+impl Library {
+    /// Library types to build for requested linkage
+    ///
+    /// If both types are requested, a disallowed one is skipped. It's an error if no requested
+    /// type is allowed.
+    fn library_types(&self, linkage: Linkage) -> GocarResult<Vec<LibraryType>> {
+        let allowed = |library_type| match library_type {
+            LibraryType::Static => !self.disallow_static,
+            LibraryType::Dynamic => !self.disallow_dynamic,
+        };
+
+        let types = linkage.library_types().iter().cloned().filter(|library_type| allowed(*library_type)).collect::<Vec<_>>();
+        if types.is_empty() {
+            Err(Error::LinkageDisallowed { library: self.target.name.clone(), linkage, })
+        } else {
+            Ok(types)
+        }
+    }
+
+    /// Builds the library, returning canonical paths of units compiled into it.
+    pub fn build(&self, env: &BuildEnv, linkage: LibraryType) -> GocarResult<Vec<PathBuf>> {
+        let mut lib_name = OsString::from("lib");
+        lib_name.push(&self.target.name);
+        let lib_spec = match linkage {
+            LibraryType::Dynamic => &env.os.dynamic_lib_spec,
+            LibraryType::Static => &env.os.static_lib_spec,
+        };
+        lib_name.push(".");
+        lib_name.push(&lib_spec.extension);
+        // Versioned dynamic libraries are named libfoo.so.MAJOR.MINOR.PATCH with symlinks
+        // libfoo.so.MAJOR (soname, used by the dynamic loader) and libfoo.so (used by the linker).
+        let version = self.version.as_ref().or(env.project.version.as_ref());
+        let (file_name, soname) = match (linkage, version) {
+            (LibraryType::Dynamic, Some(version)) => {
+                let mut soname = lib_name.clone();
+                soname.push(format!(".{}", version.major));
+                let mut file_name = lib_name.clone();
+                file_name.push(format!(".{}", version));
+                (file_name, Some(soname))
+            },
+            _ => (lib_name.clone(), None),
+        };
+        let lib_path = env.target_dir.join(&file_name);
+        let target_mtime = get_file_mtime(&lib_path)?;
+        let version_script = match (linkage, &self.version_script) {
+            (LibraryType::Dynamic, Some(script)) => Some(canonicalize_custom_wd(script, env.project_dir)?),
+            _ => None,
+        };
+        let relink = match target_mtime {
+            Some(target_mtime) => is_older(target_mtime, version_script.iter())?,
+            None => true,
+        };
+
+        let extra_options = if self.export_symbols == ExportSymbols::All {
+            Vec::new()
+        } else {
+            vec!["-fvisibility=hidden".into()]
+        };
+        let compiled = self.target.compile(env, target_mtime, lib_spec, &extra_options)?;
+        let linker = env.project.languages.get(&compiled.linker)?;
+        let links = soname.iter().map(|soname| env.target_dir.join(soname)).chain(soname.as_ref().map(|_| env.target_dir.join(&lib_name))).collect::<Vec<_>>();
+        let include_param = include_option(env.include_dir).into();
+        let export_options = self.target.unit_compile_options(env, lib_spec, &extra_options, &include_param, linker);
+
+        if compiled.up_to_date && !relink {
+            println!("  \u{1B}[32;1mUp to date\u{1B}[0m {:?}", lib_path.strip_prefix(env.strip_prefix).unwrap_or(&lib_path));
+            // Unexpected exports are reported by every build, not only the one linking the library
+            if linkage == LibraryType::Dynamic {
+                self.check_exports(&lib_path, &links, linker, export_options, env)?;
+            }
+            return Ok(compiled.sources);
+        }
+
+        let compiler = env.profile.compiler(linker);
+
+        match linkage {
+            LibraryType::Dynamic => {
+                let soname_option = soname.as_ref().map(|soname| {
+                    let mut option = OsString::from("-Wl,-soname,");
+                    option.push(soname);
+                    option
+                });
+                let version_script_option = version_script.map(|script| {
+                    let mut option = OsString::from("-Wl,--version-script=");
+                    option.push(script);
+                    option
+                });
+                let link_options = self.target.link_options(env.profile, lib_spec)
+                    .map(Into::into)
+                    .chain(soname_option)
+                    .chain(version_script_option);
+                link_using_compiler(compiler, &lib_path, link_options, &compiled.objects, env)?;
+                if let Some(soname) = soname {
+                    symlink(&file_name, env.target_dir.join(&soname))?;
+                    symlink(&soname, env.target_dir.join(&lib_name))?;
+                }
+                self.check_exports(&lib_path, &links, linker, export_options, env)?;
+                debuginfo::process(&lib_path, env)?;
+            },
+            LibraryType::Static => {
+                let archiver = match env.profile.ar() {
+                    Some(archiver) => archiver,
+                    None => Cow::Owned(env.toolchain.archiver(&compiler, env.profile.lto.unwrap_or_default())?),
+                };
+                let family = env.toolchain.detect(&compiler)?.family;
+                let archive_options = self.target.archive_options(env.profile).chain(env.profile.family_archive_options(family));
+                Library::link_static(&archiver, env.profile.ranlib().as_deref(), lib_path, archive_options, &compiled.objects, env)?
+            },
+        }
+
+        Ok(compiled.sources)
+    }
+
+    /// Checks that the shared library only exports symbols declared in public headers.
+    ///
+    /// The headers are preprocessed with options of units written in `language`, so that
+    /// declarations depend on the same macros. If the exports are denied, the library and `links`
+    /// to it are removed, otherwise the next build would consider the library up to date.
+    fn check_exports<'a, I: IntoIterator<Item=&'a PathBuf> + Clone>(&self, lib_path: &Path, links: &[PathBuf], language: &Language, options: I, env: &BuildEnv) -> GocarResult<()> {
+        if self.export_symbols == ExportSymbols::All {
+            return Ok(());
+        }
+
+        let public_headers = self.public_headers.iter().map(|header| canonicalize([env.project_dir, "src".as_ref(), header.as_ref()].iter().collect::<PathBuf>())).collect::<FsResult<HashSet<_>>>()?;
+        let mut preprocessed = Vec::new();
+        for header in &public_headers {
+            let command = Command::new(&*env.profile.compiler(language))
+                .args(env.include_dirs)
+                .args(options.clone())
+                .arg("-E");
+            let command = match &language.header_language {
+                Some(header_language) => command.arg("-x").arg(header_language),
+                None => command,
+            };
+            preprocessed.extend(command.arg(header).output()?);
+        }
+
+        let symbols = exports::check_exports(lib_path, &String::from_utf8_lossy(&preprocessed), &public_headers)?;
+        if symbols.is_empty() {
+            return Ok(());
+        }
+
+        let error = UnexpectedExports { library: self.target.name.clone(), symbols, };
+        if self.export_symbols == ExportSymbols::Deny {
+            for file in links.iter().map(AsRef::as_ref).chain(std::iter::once(lib_path)) {
+                remove_file(file)?;
+            }
+            Err(Error::UnexpectedExports(error))
+        } else {
+            println!("     \u{1B}[33;1mWarning\u{1B}[0m: {}", error);
+            Ok(())
+        }
+    }
+
+    /// Archives the objects using `archiver`, `ar` or its LTO-aware wrapper
+    ///
+    /// Linker options are not used here since `ar` doesn't understand them. `options` are passed
+    /// to `ar` as separate arguments before the operation (e.g. `--plugin`). If `ranlib` is
+    /// given, it creates the index instead of the archiver.
+    fn link_static<OP: AsRef<Path>, O: Into<OsString>, I: IntoIterator<Item=O>>(archiver: &Path, ranlib: Option<&Path>, output: OP, options: I, objects: &[PathBuf], env: &BuildEnv) -> Result<(), CommandError> {
+        let output = output.as_ref();
+
+        println!("     \u{1B}[32;1mLinking\u{1B}[0m {:?}", output.strip_prefix(env.strip_prefix).unwrap_or(output));
+        Command::new(archiver)
+            .args(options)
+            .arg(if ranlib.is_some() { "cr" } else { "crs" })
+            .arg(output)
+            .args(objects)
+            .spawn()?
+            .wait()?
+            .failure_into_error()?;
+
+        match ranlib {
+            Some(ranlib) => Command::new(ranlib)
+                .arg(output)
+                .spawn()?
+                .wait()?
+                .failure_into_error(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Compilers configured by a profile as `<key>_compiler`, by options keys of languages
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(from = "PerLanguage<CompilerKeys, PathBuf>")]
+pub struct Compilers(pub HashMap<String, PathBuf>);
+
+impl From<PerLanguage<CompilerKeys, PathBuf>> for Compilers {
+    fn from(value: PerLanguage<CompilerKeys, PathBuf>) -> Self {
+        Compilers(value.languages)
+    }
+}
+
+/// Compile options added to every profile, configured as `add_compile_options` and
+/// `add_<key>_compile_options`
+#[derive(Debug, Deserialize, Default)]
+#[serde(from = "PerLanguage<AddCompileOptionKeys, Vec<PathBuf>>")]
+pub struct AddCompileOptions(pub CompileOptions);
+
+impl From<PerLanguage<AddCompileOptionKeys, Vec<PathBuf>>> for AddCompileOptions {
+    fn from(value: PerLanguage<AddCompileOptionKeys, Vec<PathBuf>>) -> Self {
+        AddCompileOptions(value.into())
+    }
+}
+
+/// Options applied on top of a profile when building a single target.
+///
+/// Configured as `[profiles.<profile>.target.<target-name>]`.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct TargetProfile {
+    #[serde(flatten)]
+    pub compile_options: CompileOptions,
     #[serde(default)]
     pub link_options: Vec<PathBuf>,
+    #[serde(default)]
+    pub archive_options: Vec<PathBuf>,
+}
+
+impl TargetProfile {
+    fn inherit(self, parent: TargetProfile) -> Self {
+        let mut merged = parent;
+        merged.compile_options.extend(self.compile_options);
+        merged.link_options.extend(self.link_options);
+        merged.archive_options.extend(self.archive_options);
+        merged
+    }
+}
+
+/// Build profile
+///
+/// A profile may name another profile in `inherits`. Resolution is done by
+/// `Project::init_default_profiles` and follows these rules:
+///
+/// * compilers, tools and other single-valued settings not set in the child are taken from the parent
+/// * option lists are concatenated: parent options first, then child options
+/// * per-target and per-compiler-family overrides are merged by their keys using the same rule
+/// * project-level `add_*_options` are appended once, after inheritance
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Profile {
+    #[serde(default)]
+    pub inherits: Option<String>,
+    /// Compilers of languages, e.g. `c_compiler`; assembly uses the C compiler by default
+    #[serde(flatten)]
+    pub compilers: Compilers,
+    #[serde(flatten)]
+    pub compile_options: CompileOptions,
+    #[serde(default)]
+    pub link_options: Vec<PathBuf>,
+    /// Options passed to `ar` when building static libraries
+    #[serde(default)]
+    pub archive_options: Vec<PathBuf>,
+    /// Program prefixing every compile command, e.g. `ccache`
+    #[serde(default)]
+    pub compiler_launcher: Option<PathBuf>,
+    /// Restores objects from the local compile cache instead of compiling them
+    #[serde(default)]
+    pub compile_cache: Option<bool>,
+    /// Directory of the compile cache relative to the project, overrides `$GOCAR_CACHE_DIR`
+    #[serde(default)]
+    pub compile_cache_dir: Option<PathBuf>,
+    /// Link-time optimization, `off`, `thin` or `full`
+    #[serde(default)]
+    pub lto: Option<Lto>,
+    /// Archiver creating static libraries, `$AR` by default
+    #[serde(default)]
+    pub ar: Option<PathBuf>,
+    /// Indexes static libraries after archiving them, `$RANLIB` by default
+    #[serde(default)]
+    pub ranlib: Option<PathBuf>,
+    /// Linker used by the compiler, e.g. `lld`, `mold` or `ld.gold`, `$LD` by default
+    #[serde(default)]
+    pub linker: Option<PathBuf>,
+    /// Strips binaries and libraries, `$STRIP` by default
+    ///
+    /// Not named `strip`, which is the mode of stripping.
+    #[serde(default)]
+    pub strip_program: Option<PathBuf>,
+    /// Splits debug information out of binaries and libraries, `$OBJCOPY` by default
+    #[serde(default)]
+    pub objcopy: Option<PathBuf>,
+    /// Debug information and symbols removed from binaries and shared libraries
+    #[serde(default)]
+    pub strip: Option<Strip>,
+    /// Moves debug information of binaries and shared libraries to separate `.debug` files
+    #[serde(default)]
+    pub split_debuginfo: Option<bool>,
+    /// Where split debug information is placed
+    #[serde(default)]
+    pub debuginfo_layout: Option<DebuginfoLayout>,
+    #[serde(default)]
+    pub target: HashMap<PathBuf, TargetProfile>,
+    /// Options applied when the compiler is of the given family, e.g. `[compiler_family.clang]`
+    #[serde(default)]
+    pub compiler_family: HashMap<CompilerFamily, TargetProfile>,
 }
 
 impl Profile {
     pub fn release() -> Self {
         Profile {
-            c_compiler: default_c_compiler(),
-            cpp_compiler: default_cpp_compiler(),
             compile_options: CompileOptions::release(),
-            link_options: Vec::new(),
+            ..Default::default()
         }
     }
 
     pub fn debug() -> Self {
         Profile {
-            c_compiler: default_c_compiler(),
-            cpp_compiler: default_cpp_compiler(),
             compile_options: CompileOptions::debug(),
-            link_options: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Compiler of the language
+    ///
+    /// Falls back to the compiler configured for `compiler_fallback` of the language and then to
+    /// the default compiler of the language.
+    pub fn compiler<'a>(&'a self, language: &'a Language) -> Cow<'a, Path> {
+        std::iter::once(language.options_key())
+            .chain(language.compiler_fallback.as_deref())
+            .find_map(|key| self.compilers.0.get(key))
+            .map_or_else(|| language.default_compiler(), |compiler| Cow::Borrowed(&**compiler))
+    }
+
+    /// Archiver configured by the profile or `$AR`
+    ///
+    /// If none is configured, the build uses `ar` or its LTO-aware wrapper.
+    pub fn ar(&self) -> Option<Cow<'_, Path>> {
+        configured_tool(&self.ar, "AR")
+    }
+
+    /// `ranlib` configured by the profile or `$RANLIB`
+    ///
+    /// If none is configured, the archiver creates the index itself.
+    pub fn ranlib(&self) -> Option<Cow<'_, Path>> {
+        configured_tool(&self.ranlib, "RANLIB")
+    }
+
+    /// Option making compiler of `family` use the linker configured by the profile or `$LD`
+    pub fn linker_option(&self, family: CompilerFamily) -> Option<OsString> {
+        configured_tool(&self.linker, "LD").and_then(|linker| fuse_ld_option(&linker, family))
+    }
+
+    /// `strip` configured by the profile, `$STRIP` or `strip`
+    pub fn strip_program(&self) -> Cow<'_, Path> {
+        configured_tool(&self.strip_program, "STRIP").unwrap_or(Cow::Borrowed("strip".as_ref()))
+    }
+
+    /// `objcopy` configured by the profile, `$OBJCOPY` or `objcopy`
+    pub fn objcopy(&self) -> Cow<'_, Path> {
+        configured_tool(&self.objcopy, "OBJCOPY").unwrap_or(Cow::Borrowed("objcopy".as_ref()))
+    }
+
+    /// Compile options overriden for the target named `target`
+    pub fn target_compile_options<'a>(&'a self, target: &Path, language: &'a Language) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        self.target.get(target).into_iter().flat_map(move |overrides| overrides.compile_options.all(language))
+    }
+
+    /// Link options overriden for the target named `target`
+    pub fn target_link_options<'a>(&'a self, target: &Path) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        self.target.get(target).into_iter().flat_map(|overrides| &overrides.link_options)
+    }
+
+    /// Archive options overriden for the target named `target`
+    pub fn target_archive_options<'a>(&'a self, target: &Path) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        self.target.get(target).into_iter().flat_map(|overrides| &overrides.archive_options)
+    }
+
+    /// Compile options for units of `language` compiled by a compiler of `family`
+    pub fn family_compile_options<'a>(&'a self, family: CompilerFamily, language: &'a Language) -> impl Iterator<Item=&'a PathBuf> + Clone {
+        self.compiler_family.get(&family).into_iter().flat_map(move |overrides| overrides.compile_options.all(language))
+    }
+
+    /// Link options used when linking with a compiler of `family`
+    pub fn family_link_options(&self, family: CompilerFamily) -> impl Iterator<Item=&PathBuf> + Clone {
+        self.compiler_family.get(&family).into_iter().flat_map(|overrides| &overrides.link_options)
+    }
+
+    /// Archive options used when the objects were compiled by a compiler of `family`
+    pub fn family_archive_options(&self, family: CompilerFamily) -> impl Iterator<Item=&PathBuf> + Clone {
+        self.compiler_family.get(&family).into_iter().flat_map(|overrides| &overrides.archive_options)
+    }
+
+    /// Merges `self` with its already resolved parent.
+    fn inherit(self, parent: Profile) -> Self {
+        let target = merge_overrides(parent.target, self.target);
+        let compiler_family = merge_overrides(parent.compiler_family, self.compiler_family);
+
+        let mut compile_options = parent.compile_options;
+        compile_options.extend(self.compile_options);
+        let mut link_options = parent.link_options;
+        link_options.extend(self.link_options);
+        let mut archive_options = parent.archive_options;
+        archive_options.extend(self.archive_options);
+        let mut compilers = parent.compilers;
+        compilers.0.extend(self.compilers.0);
+
+        Profile {
+            inherits: self.inherits,
+            compilers,
+            compile_options,
+            link_options,
+            archive_options,
+            compiler_launcher: self.compiler_launcher.or(parent.compiler_launcher),
+            compile_cache: self.compile_cache.or(parent.compile_cache),
+            compile_cache_dir: self.compile_cache_dir.or(parent.compile_cache_dir),
+            lto: self.lto.or(parent.lto),
+            ar: self.ar.or(parent.ar),
+            ranlib: self.ranlib.or(parent.ranlib),
+            linker: self.linker.or(parent.linker),
+            strip_program: self.strip_program.or(parent.strip_program),
+            objcopy: self.objcopy.or(parent.objcopy),
+            strip: self.strip.or(parent.strip),
+            split_debuginfo: self.split_debuginfo.or(parent.split_debuginfo),
+            debuginfo_layout: self.debuginfo_layout.or(parent.debuginfo_layout),
+            target,
+            compiler_family,
         }
     }
+}
+
+/// Merges overrides of a child profile into overrides of its parent by their keys
+fn merge_overrides<K: Eq + std::hash::Hash>(mut parent: HashMap<K, TargetProfile>, child: HashMap<K, TargetProfile>) -> HashMap<K, TargetProfile> {
+    for (key, overrides) in child {
+        let merged = match parent.remove(&key) {
+            Some(parent_overrides) => overrides.inherit(parent_overrides),
+            None => overrides,
+        };
+        parent.insert(key, merged);
+    }
+
+    parent
+}
 
-    pub fn compiler(&self, compiler: Compiler) -> &Path {
-        match compiler {
-            Compiler::C => &self.c_compiler,
-            Compiler::Cpp => &self.cpp_compiler,
+fn configured_tool<'a>(configured: &'a Option<PathBuf>, env_var: &str) -> Option<Cow<'a, Path>> {
+    configured
+        .as_deref()
+        .map(Cow::Borrowed)
+        .or_else(|| std::env::var_os(env_var).map(|tool| Cow::Owned(tool.into())))
+}
+
+/// Linkers selectable by `-fuse-ld` in both GCC and clang
+const FUSE_LD_LINKERS: [&str; 4] = ["bfd", "gold", "lld", "mold"];
+
+/// Converts the linker to an option selecting it
+///
+/// `-fuse-ld` only accepts known names, so `ld.gold` or `/usr/bin/ld.lld` are passed as `gold`
+/// and `lld`. Other linkers can only be given to clang by their path using `--ld-path`, for GCC
+/// they're ignored with a warning. Plain `ld` is the default linker, so no option is needed.
+fn fuse_ld_option(linker: &Path, family: CompilerFamily) -> Option<OsString> {
+    let name = linker.file_name()?.to_str()?;
+    let name = name.strip_prefix("ld.").unwrap_or(name);
+    if name == "ld" {
+        return None;
+    }
+
+    if FUSE_LD_LINKERS.contains(&name) {
+        return Some(format!("-fuse-ld={}", name).into());
+    }
+
+    if family == CompilerFamily::Clang && linker.parent().map_or(false, |parent| !parent.as_os_str().is_empty()) {
+        let mut option = OsString::from("--ld-path=");
+        option.push(linker);
+        return Some(option);
+    }
+
+    println!("     \u{1B}[33;1mWarning\u{1B}[0m: ignoring linker {:?}, the compiler can only use {} or, if it's clang, a path to the linker", linker, FUSE_LD_LINKERS.join(", "));
+    None
+}
+
+/// Resolves `inherits` of all profiles.
+///
+/// Profiles are processed in order of their names so that errors are reported deterministically.
+fn resolve_profiles(profiles: &HashMap<String, Profile>) -> Result<HashMap<String, Profile>, ProfileError> {
+    let mut names = profiles.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut resolved = HashMap::with_capacity(profiles.len());
+    for name in names {
+        let mut chain = vec![name];
+        let mut current = &profiles[name];
+        while let Some(parent) = &current.inherits {
+            if chain.contains(&parent) {
+                chain.push(parent);
+                return Err(ProfileError::InheritanceCycle(chain.into_iter().cloned().collect()));
+            }
+
+            current = profiles.get(parent).ok_or_else(|| ProfileError::UnknownParent { profile: (*chain.last().unwrap()).clone(), parent: parent.clone(), })?;
+            chain.push(parent);
         }
+
+        let root = profiles[chain.pop().unwrap()].clone();
+        let profile = chain.into_iter().rev().fold(root, |parent, name| profiles[name].clone().inherit(parent));
+        resolved.insert(name.clone(), profile);
     }
+
+    Ok(resolved)
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Dependency {
     path: PathBuf,
     #[serde(default)]
-    linkage: Option<LibraryType>,
+    linkage: Option<Linkage>,
     #[serde(default)]
     config_headers: Vec<PathBuf>,
 }
@@ -886,6 +1888,10 @@ pub struct DetachedHeaders {
     sources: PathBuf,
 }
 
+fn default_header_extensions() -> Vec<String> {
+    ["h", "hh", "hpp", "hxx", "ipp"].iter().map(|extension| (*extension).to_owned()).collect()
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Project {
     #[serde(default)]
@@ -893,16 +1899,18 @@ pub struct Project {
     #[serde(default)]
     pub lib: Vec<Library>,
     #[serde(default)]
+    #[serde(alias = "profile")]
     pub profiles: std::collections::HashMap<String, Profile>,
-    #[serde(default)]
-    pub add_compile_options: Vec<PathBuf>,
-    #[serde(default)]
-    pub add_c_compile_options: Vec<PathBuf>,
-    #[serde(default)]
-    pub add_cpp_compile_options: Vec<PathBuf>,
+    /// Languages configured in addition to (or instead of) built-in ones
+    #[serde(default, rename = "language")]
+    pub languages: Languages,
+    #[serde(flatten)]
+    pub add_compile_options: AddCompileOptions,
     #[serde(default)]
     pub add_link_options: Vec<PathBuf>,
     #[serde(default)]
+    pub add_archive_options: Vec<PathBuf>,
+    #[serde(default)]
     pub ignore_missing_sources: bool,
     #[serde(default)]
     pub estimate_headers_only: bool,
@@ -910,12 +1918,33 @@ pub struct Project {
     pub detached_headers: Vec<DetachedHeaders>,
     #[serde(default)]
     pub post_compile: Option<PathBuf>,
+    /// Program run before scanning sources, which may generate files into `target/<profile>/gen`
+    #[serde(default)]
+    pub build_script: Option<PathBuf>,
+    /// Program run after the project is built
+    #[serde(default)]
+    pub post_build_script: Option<PathBuf>,
+    /// Rules generating C sources and headers from other inputs
+    #[serde(default)]
+    pub generator: Vec<Generator>,
     #[serde(default)]
     pub headers_only: HashSet<PathBuf>,
+    /// Configure-time checks by names of macros defined in the generated `config.h`
+    #[serde(default)]
+    pub checks: BTreeMap<String, Check>,
+    /// Extensions of headers found while scanning units
+    #[serde(default = "default_header_extensions")]
+    pub header_extensions: Vec<String>,
+    /// Scans units for C++20 modules and compiles interface units before their importers
+    #[serde(default)]
+    pub modules: bool,
     #[serde(default)]
     pub dependencies: HashMap<String, Dependency>,
     #[serde(default)]
     pub include_dirs: Vec<PathBuf>,
+    /// Version of the project used by libraries which don't specify their own
+    #[serde(default)]
+    pub version: Option<Version>,
 }
 
 impl Project {
@@ -927,22 +1956,45 @@ impl Project {
         let mut project_data = Vec::new();
         file_open(&file_path)?.read_to_end(&mut project_data).err_ctx(|| (file_path, "read file"))?;
         let mut project = toml::from_slice::<Project>(&project_data).unwrap();
-        project.init_default_profiles();
+        project.init_default_profiles()?;
         Ok(project)
     }
 
-    pub fn init_default_profiles(&mut self) {
+    /// Adds built-in profiles, resolves inheritance and applies project-level options.
+    pub fn init_default_profiles(&mut self) -> GocarResult<()> {
+        self.check_language_keys()?;
         self.profiles.entry("release".to_owned()).or_insert_with(Profile::release);
         self.profiles.entry("debug".to_owned()).or_insert_with(Profile::debug);
-        for (_, profile) in &mut self.profiles {
-            profile.compile_options.common.extend_from_slice(&self.add_compile_options);
-            profile.compile_options.c.extend_from_slice(&self.add_c_compile_options);
-            profile.compile_options.cpp.extend_from_slice(&self.add_cpp_compile_options);
+        self.profiles = resolve_profiles(&self.profiles)?;
+        for profile in self.profiles.values_mut() {
+            profile.compile_options.extend(self.add_compile_options.0.clone());
             profile.link_options.extend_from_slice(&self.add_link_options);
+            profile.archive_options.extend_from_slice(&self.add_archive_options);
         }
+
+        Ok(())
     }
 
-    pub fn build_dependencies<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: LibraryType) -> GocarResult<(PathBuf, Vec<OsString>, Vec<OsString>)> {
+    /// Checks that per-language keys, e.g. `cpp_compile_options`, name known languages.
+    fn check_language_keys(&self) -> Result<(), UnknownLanguageKey> {
+        let languages = &self.languages;
+        languages.check_options_keys::<AddCompileOptionKeys, _>(self.add_compile_options.0.languages.keys())?;
+        for profile in self.profiles.values() {
+            languages.check_options_keys::<CompilerKeys, _>(profile.compilers.0.keys())?;
+            let overrides = profile.target.values().chain(profile.compiler_family.values()).map(|target| &target.compile_options);
+            for options in std::iter::once(&profile.compile_options).chain(overrides) {
+                languages.check_options_keys::<CompileOptionKeys, _>(options.languages.keys())?;
+            }
+        }
+        let targets = self.lib.iter().map(|lib| &lib.target.compile_options).chain(self.bin.iter().map(|bin| &bin.target.compile_options));
+        for options in targets {
+            languages.check_options_keys::<CompileOptionKeys, _>(options.languages.keys())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn build_dependencies<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: Linkage, instrumentation: &Instrumentation) -> GocarResult<(PathBuf, Vec<OsString>, Vec<OsString>)> {
         let include_dir = [target_dir.as_ref(), "deps".as_ref(), "include".as_ref()].iter().collect::<PathBuf>();
         let mut lib_dirs = Vec::with_capacity(self.dependencies.len());
         let mut libs = Vec::with_capacity(self.dependencies.len());
@@ -953,10 +2005,11 @@ impl Project {
         for (dep_name, dep) in &self.dependencies {
             let mut project = Project::load_from_dir(&dep.path)?;
             let dep_lib_dir = [target_dir.as_ref(), "deps".as_ref(), "lib".as_ref(), dep_name.as_ref()].iter().collect::<PathBuf>();
-            let dep_include_dir = include_dir.join(&dep_name);
+            let dep_include_dir = include_dir.join(dep_name);
             create_dir_all(&dep_lib_dir)?;
             create_dir_all(&dep_include_dir)?;
-            let extra_include = if dep.config_headers.len() > 0 {
+            let dep_lib_dir = canonicalize(dep_lib_dir)?;
+            let extra_include = if !dep.config_headers.is_empty() {
                 dep.copy_config_headers(&project_dir, &dep_include_dir, &mut project)?;
                 Some(&*dep_include_dir)
             } else {
@@ -965,10 +2018,10 @@ impl Project {
             let linkage = dep.linkage.unwrap_or(linkage);
             if dep.path.is_relative() {
                 let dep_path = project_dir.as_ref().join(&dep.path);
-                project.build_libraries(&dep_lib_dir, &dep_path, profile_name, linkage, extra_include)?;
+                project.build_libraries(&dep_lib_dir, &dep_path, profile_name, linkage, instrumentation, extra_include)?;
                 project.copy_headers(dep_include_dir, &dep_path)?;
             } else {
-                project.build_libraries(&dep_lib_dir, &dep.path, profile_name, linkage, extra_include)?;
+                project.build_libraries(&dep_lib_dir, &dep.path, profile_name, linkage, instrumentation, extra_include)?;
                 project.copy_headers(dep_include_dir, &dep.path)?;
             }
 
@@ -976,6 +2029,13 @@ impl Project {
             lib_dir.push(&dep_lib_dir);
             lib_dirs.push(lib_dir);
 
+            // Make dynamically linked dependencies loadable without setting LD_LIBRARY_PATH
+            if linkage != Linkage::Static {
+                let mut rpath = OsString::from("-Wl,-rpath,");
+                rpath.push(&dep_lib_dir);
+                lib_dirs.push(rpath);
+            }
+
             for lib in &project.lib {
                 let mut lib_arg = OsString::from("-l");
                 lib_arg.push(&lib.target.name);
@@ -986,64 +2046,392 @@ impl Project {
         Ok((include_dir, lib_dirs, libs))
     }
 
-    fn with_build_env<F: FnOnce(&BuildEnv) -> GocarResult<()>>(&self, target_dir: &Path, project_dir: &Path, profile_name: &str, linkage: LibraryType, extra_include: Option<&Path>, f: F) -> GocarResult<()> {
+    #[allow(clippy::too_many_arguments)]
+    fn with_build_env<T, F: FnOnce(&BuildEnv) -> GocarResult<T>>(&self, target_dir: &Path, project_dir: &Path, profile_name: &str, linkage: Linkage, instrumentation: &Instrumentation, extra_include: Option<&Path>, f: F) -> GocarResult<T> {
         let profile = self.profiles.get(profile_name).ok_or(Error::InvalidProfileName)?;
-        let (include_dir, lib_dirs, libs) = self.build_dependencies(target_dir, project_dir, profile_name, linkage)?;
+        let profile = instrumentation.apply(profile);
+        let (include_dir, lib_dirs, mut libs) = self.build_dependencies(target_dir, project_dir, profile_name, linkage, instrumentation)?;
         let strip_prefix = std::env::current_dir().unwrap_or_else(|_| PathBuf::new());
-        let headers_only = self.headers_only.iter().map(|path| canonicalize_custom_wd(path, project_dir)).collect::<Result<HashSet<_>, _>>()?;
+        let mut headers_only = self.headers_only.iter().map(|path| canonicalize_custom_wd(path, project_dir)).collect::<Result<HashSet<_>, _>>()?;
+        // Precompiled headers usually have no source of their own
+        let precompiled_headers = self.lib.iter().map(|lib| &lib.target.precompiled_header).chain(self.bin.iter().map(|bin| &bin.target.precompiled_header));
+        for header in precompiled_headers.flatten() {
+            let header = canonicalize_custom_wd(header, project_dir)?;
+            if header_to_unit(&*header, &self.languages, std::iter::empty())?.is_none() {
+                headers_only.insert(header);
+            }
+        }
         let mut include_dirs = self.include_dirs
             .iter()
             .map(|path| canonicalize_custom_wd(path, project_dir))
             .map(|dir| dir.map(include_option))
             .collect::<Result<Vec<_>, _>>()?;
 
+        // Headers generated by gocar
+        let generated_include_dir = target_dir.join("include");
+        create_dir_all(&generated_include_dir)?;
+        for (name, content) in &[("gocar_test.h", GOCAR_TEST_HEADER), ("gocar_bench.h", GOCAR_BENCH_HEADER)] {
+            let header = generated_include_dir.join(name);
+            write_if_changed(&header, content.as_bytes())?;
+            headers_only.insert(canonicalize(header)?);
+        }
+        for lib in self.lib.iter().filter(|lib| lib.export_symbols != ExportSymbols::All) {
+            let header = exports::write_export_header(&lib.target.name, &generated_include_dir)?;
+            headers_only.insert(canonicalize(header)?);
+        }
+        include_dirs.push(include_option(canonicalize(generated_include_dir)?));
+
+        let mut generated_units = Vec::new();
+        // Files generated by the build script and generators are placed in the same directory
+        if self.build_script.is_some() || !self.generator.is_empty() {
+            let gen_dir = target_dir.join("gen");
+            create_dir_all(&gen_dir)?;
+            let gen_dir = canonicalize(gen_dir)?;
+
+            if let Some(script) = &self.build_script {
+                let output = build_script::run(script, &gen_dir, &target_dir.join("build-script-output"), project_dir, profile_name, &profile, &self.languages)?;
+                for dir in output.include_dirs {
+                    include_dirs.push(include_option(canonicalize_custom_wd(dir, project_dir)?));
+                }
+                libs.extend(output.link_libs.iter().map(|lib| prefixed_option("-l", lib)));
+            }
+
+            for generator in &self.generator {
+                for output in generator.run(project_dir, &gen_dir, &strip_prefix)? {
+                    if self.languages.determine_from_file(&output).is_some() {
+                        generated_units.push(canonicalize(output)?);
+                    }
+                }
+            }
+
+            headers_only.extend(build_script::generated_headers_only(&gen_dir, self)?);
+            include_dirs.push(include_option(&gen_dir));
+        }
+
+        let toolchain = Toolchain::load(target_dir.join("compilers"));
+        if !self.checks.is_empty() {
+            let language = self.languages.get("c")?;
+            let compiler = profile.compiler(language);
+            let compiler_info = toolchain.detect(&compiler)?;
+            let options = include_dirs
+                .iter()
+                .cloned()
+                .chain(std::iter::once(include_option(&include_dir)))
+                .chain(profile.compile_options.all(language).chain(profile.family_compile_options(compiler_info.family, language)).map(Into::into))
+                .collect::<Vec<_>>();
+            let config = checks::configure(&self.checks, &compiler, &compiler_info, &options, &lib_dirs, target_dir)?;
+            let header = target_dir.join("include").join("config.h");
+            write_if_changed(&header, config.as_bytes())?;
+            headers_only.insert(canonicalize(header)?);
+        }
+
         if let Some(extra_include) = extra_include {
             include_dirs.push(include_option(extra_include));
         }
 
+        // Objects instrumented for coverage come with notes files, which aren't cached
+        let compile_cache = match profile.compile_cache {
+            Some(true) if !instrumentation.coverage() => match &profile.compile_cache_dir {
+                Some(dir) => Some(CompileCache::open_in(project_dir.join(dir))),
+                None => {
+                    let cache = CompileCache::open();
+                    if cache.is_none() {
+                        println!("     \u{1B}[33;1mWarning\u{1B}[0m: compile cache disabled, HOME is not set");
+                    }
+                    cache
+                },
+            },
+            _ => None,
+        };
+
         let env = BuildEnv {
-            target_dir: target_dir,
-            project_dir: project_dir,
+            target_dir,
+            project_dir,
             include_dir: &include_dir,
             include_dirs: &include_dirs,
             lib_dirs: &lib_dirs,
             libs: &libs,
-            profile,
+            profile: &profile,
             project: self,
             strip_prefix: &strip_prefix,
             headers_only: &headers_only,
+            generated_units: &generated_units,
+            linked_modules: &BTreeMap::new(),
             os: OsSpec::linux(),
+            compile_cache: compile_cache.as_ref(),
+            toolchain: &toolchain,
         };
 
-        f(&env)
+        let result = f(&env);
+        if let Some(cache) = &compile_cache {
+            cache.print_stats();
+        }
+        toolchain.save()?;
+        result
     }
 
-    fn build_libs(&self, env: &BuildEnv, linkage: LibraryType) -> GocarResult<()> {
+    /// Builds libraries of the project, returning canonical paths of units compiled into each of
+    /// them, in the order of `lib`.
+    fn build_libs(&self, env: &BuildEnv, linkage: Linkage) -> GocarResult<Vec<HashSet<PathBuf>>> {
+        let mut sources = Vec::with_capacity(self.lib.len());
         for lib in &self.lib {
-            lib.build(&env, linkage)?;
+            let mut lib_sources = HashSet::new();
+            for library_type in lib.library_types(linkage)? {
+                lib_sources.extend(lib.build(env, library_type)?);
+            }
+            sources.push(lib_sources);
         }
 
-        Ok(())
+        Ok(sources)
     }
 
     fn build_bins(&self, env: &BuildEnv) -> GocarResult<()> {
         for bin in &self.bin {
-            bin.build(&env)?;
+            bin.build(env)?;
         }
 
         Ok(())
     }
 
-    pub fn build<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: LibraryType) -> GocarResult<()> {
-        self.with_build_env(target_dir.as_ref(), project_dir.as_ref(), profile_name, linkage, None, |env| {
+    pub fn build<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: Linkage, instrumentation: &Instrumentation) -> GocarResult<()> {
+        self.with_build_env(target_dir.as_ref(), project_dir.as_ref(), profile_name, linkage, instrumentation, None, |env| {
             self.build_libs(env, linkage)?;
-            self.build_bins(env)
+            self.build_bins(env)?;
+
+            match &self.post_build_script {
+                Some(script) => build_script::run_post_build(script, env.target_dir, env.project_dir, profile_name),
+                None => Ok(()),
+            }
+        })
+    }
+
+    pub fn build_libraries<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: Linkage, instrumentation: &Instrumentation, extra_include: Option<&Path>) -> GocarResult<()> {
+        self.with_build_env(target_dir.as_ref(), project_dir.as_ref(), profile_name, linkage, instrumentation, extra_include, |env| {
+            self.build_libs(env, linkage).map(drop)
+        })
+    }
+
+    /// Builds unit tests written inside of `sources` of `lib` and returns those matching the filter.
+    ///
+    /// The sources are compiled with `-DGOCAR_TEST` into `<out_dir>/<lib>` and linked with a
+    /// generated runner, which lists tests registered using `GOCAR_TEST` and runs them by name.
+    /// The runner isn't linked against the library itself. Libraries without unit tests are
+    /// skipped.
+    fn build_unit_tests(&self, lib: &Library, sources: &HashSet<PathBuf>, env: &BuildEnv, out_dir: &Path, filter: Option<&str>) -> GocarResult<Vec<test_runner::TestBinary>> {
+        if !test_runner::has_unit_tests(sources)? {
+            return Ok(Vec::new());
+        }
+
+        let unit_dir = out_dir.join(&lib.target.name);
+        create_dir_all(&unit_dir)?;
+        let runner_source = unit_dir.join("gocar_test_main.c");
+        write_if_changed(&runner_source, b"#define GOCAR_TEST_MAIN\n#include \"gocar_test.h\"\n")?;
+
+        let mut compile_options = lib.target.compile_options.clone();
+        compile_options.common.push("-DGOCAR_TEST".into());
+        let mut root_files = lib.target.root_files.clone();
+        root_files.insert(canonicalize(runner_source)?);
+
+        let binary = Binary {
+            target: Target {
+                name: lib.target.name.clone(),
+                root_files,
+                compile_options,
+                link_options: lib.target.link_options.clone(),
+                archive_options: Vec::new(),
+                ignore_files: lib.target.ignore_files.clone(),
+                precompiled_header: lib.target.precompiled_header.clone(),
+                unity_build: lib.target.unity_build,
+                unity_batch_size: lib.target.unity_batch_size,
+                unity_exclude: lib.target.unity_exclude.clone(),
+                _phantom: Default::default(),
+            }
+        };
+
+        // The runner contains the library, linking it too would load a second copy of its code
+        let lib_option = prefixed_option("-l", &lib.target.name);
+        let libs = env.libs.iter().filter(|lib| **lib != lib_option).cloned().collect::<Vec<_>>();
+        let env = BuildEnv {
+            target_dir: &unit_dir,
+            libs: &libs,
+            os: env.os.clone(),
+            ..*env
+        };
+        binary.build(&env)?;
+
+        let runner = binary.output_path(&env);
+        let list = Command::new(&runner).arg("--list").output()?;
+        let list = String::from_utf8_lossy(&list);
+        let tests = test_runner::parse_unit_tests(&lib.target.name, &list)?
+            .into_iter()
+            .map(|test| (format!("{}::{}", lib.target.name.display(), test), test))
+            .filter(|(name, _)| filter.map_or(true, |filter| name.contains(filter)))
+            .map(|(name, test)| test_runner::TestBinary { name, path: runner.clone(), args: vec![test.into()], })
+            .collect();
+
+        Ok(tests)
+    }
+
+    /// Builds libraries of the project and calls `f` with environment for programs using them.
+    ///
+    /// Public headers of the libraries are visible to the programs the same way as to dependent
+    /// projects and the libraries are linked before dependencies which they may use. `f` also
+    /// gets canonical paths of units compiled into each library, so that they can be ignored.
+    fn with_project_libs<T, F: FnOnce(&BuildEnv, Vec<HashSet<PathBuf>>) -> GocarResult<T>>(&self, env: &BuildEnv, linkage: Linkage, f: F) -> GocarResult<T> {
+        let lib_sources = self.build_libs(env, linkage)?;
+
+        let mut include_dirs = env.include_dirs.to_vec();
+        for lib in &self.lib {
+            for header in &lib.public_headers {
+                let dir = include_option(canonicalize([env.project_dir, "src".as_ref(), header.as_ref()].iter().collect::<PathBuf>())?.parent().unwrap());
+                if !include_dirs.contains(&dir) {
+                    include_dirs.push(dir);
+                }
+            }
+        }
+
+        let mut lib_dirs = vec![prefixed_option("-L", env.target_dir)];
+        if linkage != Linkage::Static {
+            lib_dirs.push(prefixed_option("-Wl,-rpath,", canonicalize(env.target_dir)?));
+        }
+        lib_dirs.extend_from_slice(env.lib_dirs);
+        let libs = self.lib
+            .iter()
+            .map(|lib| prefixed_option("-l", &lib.target.name))
+            .chain(env.libs.iter().cloned())
+            .collect::<Vec<_>>();
+
+        // Programs import modules of the libraries instead of compiling them again. The linker
+        // prefers dynamic libraries, so their BMIs are used if they were built.
+        let mut linked_modules = BTreeMap::new();
+        if self.modules {
+            for lib in &self.lib {
+                let spec = if lib.library_types(linkage)?.contains(&LibraryType::Dynamic) {
+                    &env.os.dynamic_lib_spec
+                } else {
+                    &env.os.static_lib_spec
+                };
+                let mapper = lib.target.modules_mapper(env, spec);
+                let mapping = std::fs::read_to_string(&mapper).err_ctx(|| (mapper.clone(), "read file"))?;
+                linked_modules.extend(mapping.lines().filter_map(|line| {
+                    let mut parts = line.splitn(2, ' ');
+                    Some((parts.next()?.to_owned(), PathBuf::from(parts.next()?)))
+                }));
+            }
+        }
+
+        let env = BuildEnv {
+            include_dirs: &include_dirs,
+            lib_dirs: &lib_dirs,
+            libs: &libs,
+            linked_modules: &linked_modules,
+            os: env.os.clone(),
+            ..*env
+        };
+
+        f(&env, lib_sources)
+    }
+
+    /// Builds examples from `examples` directory, returning their names and paths.
+    ///
+    /// Each `.c(pp)` file in `examples` is a program linked against libraries of the project and
+    /// its dependencies. Examples are placed in `examples` subdirectory of the target directory.
+    /// If `name` is given, only the example with this name is built.
+    pub fn build_examples<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: Linkage, instrumentation: &Instrumentation, name: Option<&str>) -> GocarResult<Vec<(String, PathBuf)>> {
+        let mut examples = test_runner::discover_programs(&project_dir.as_ref().join("examples"), None)?;
+        if let Some(name) = name {
+            examples.retain(|(example, _)| example == name);
+            if examples.is_empty() {
+                return Err(Error::UnknownExample(name.to_owned()));
+            }
+        }
+
+        let examples_dir = target_dir.as_ref().join("examples");
+        create_dir_all(&examples_dir)?;
+
+        self.with_build_env(target_dir.as_ref(), project_dir.as_ref(), profile_name, linkage, instrumentation, None, |env| {
+            self.with_project_libs(env, linkage, |env, lib_sources| {
+                let lib_sources = lib_sources.into_iter().flatten().collect::<HashSet<_>>();
+                let env = BuildEnv {
+                    target_dir: &examples_dir,
+                    os: env.os.clone(),
+                    ..*env
+                };
+
+                let mut binaries = Vec::with_capacity(examples.len());
+                for (name, source) in examples {
+                    let binary = Binary::program(name.clone(), source, lib_sources.clone());
+                    binary.build(&env)?;
+                    binaries.push((name, binary.output_path(&env)));
+                }
+
+                Ok(binaries)
+            })
+        })
+    }
+
+    /// Builds and runs unit tests of libraries and integration tests from `tests` directory.
+    ///
+    /// Each `.c(pp)` file in `tests` is a separate program, which passes if it exits with zero
+    /// status. Each unit test runs in a separate process too. Tests which fail to build are
+    /// reported as failed without stopping other tests.
+    ///
+    /// Libraries of the project are built first and integration tests are linked against them
+    /// instead of compiling their sources again.
+    pub fn test<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: Linkage, instrumentation: &Instrumentation, options: &TestOptions) -> GocarResult<TestReport> {
+        let unit_tests_dir = target_dir.as_ref().join("unit_tests");
+        let target_dir = target_dir.as_ref().join("integration_tests");
+        create_dir_all(&target_dir)?;
+        let tests = test_runner::discover_programs(&project_dir.as_ref().join("tests"), options.filter.as_deref())?;
+
+        self.with_build_env(&target_dir, project_dir.as_ref(), profile_name, linkage, instrumentation, None, |env| {
+            self.with_project_libs(env, linkage, |env, lib_sources| {
+                let mut binaries = Vec::with_capacity(tests.len());
+                let mut build_failures = Vec::new();
+                for (lib, sources) in self.lib.iter().zip(&lib_sources) {
+                    match self.build_unit_tests(lib, sources, env, &unit_tests_dir, options.filter.as_deref()) {
+                        Ok(unit_tests) => binaries.extend(unit_tests),
+                        Err(error) => build_failures.push(test_build_failure(lib.target.name.to_string_lossy().into_owned(), error)),
+                    }
+                }
+
+                let lib_sources = lib_sources.into_iter().flatten().collect::<HashSet<_>>();
+
+                for (name, source) in tests {
+                    let binary = Binary::program(name.clone(), source, lib_sources.clone());
+                    match binary.build(env) {
+                        Ok(()) => binaries.push(test_runner::TestBinary { name, path: binary.output_path(env), args: Vec::new(), }),
+                        Err(error) => build_failures.push(test_build_failure(name, error)),
+                    }
+                }
+
+                test_runner::run_tests(binaries, build_failures, options, env).map_err(Into::into)
+            })
         })
     }
 
-    pub fn build_libraries<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: LibraryType, extra_include: Option<&Path>) -> GocarResult<()> {
-        self.with_build_env(target_dir.as_ref(), project_dir.as_ref(), profile_name, linkage, extra_include, |env| {
-            self.build_libs(env, linkage)
+    /// Builds and runs benchmarks from `benches` directory.
+    ///
+    /// Each `.c(pp)` file in `benches` is a separate program linked against libraries of the
+    /// project, which measures its code using `gocar_bench.h`. Results are compared with the
+    /// previous run stored in the target directory.
+    pub fn bench<TP: AsRef<Path>, PP: AsRef<Path>>(&self, target_dir: TP, project_dir: PP, profile_name: &str, linkage: Linkage, filter: Option<&str>) -> GocarResult<Vec<BenchResult>> {
+        let target_dir = target_dir.as_ref().join("benches");
+        create_dir_all(&target_dir)?;
+        let benches = test_runner::discover_programs(&project_dir.as_ref().join("benches"), filter)?;
+
+        self.with_build_env(&target_dir, project_dir.as_ref(), profile_name, linkage, &Instrumentation::default(), None, |env| {
+            self.with_project_libs(env, linkage, |env, lib_sources| {
+                let lib_sources = lib_sources.into_iter().flatten().collect::<HashSet<_>>();
+                let mut binaries = Vec::with_capacity(benches.len());
+                for (name, source) in benches {
+                    let binary = Binary::program(name.clone(), source, lib_sources.clone());
+                    binary.build(env)?;
+                    binaries.push((name, binary.output_path(env)));
+                }
+
+                bench::run_benches(binaries, env)
+            })
         })
     }
 
@@ -1054,8 +2442,466 @@ impl Project {
                 let dest = [target_dir.as_ref(), header_relative.file_name().unwrap().as_ref()].iter().collect::<PathBuf>();
                 std::fs::copy(&header, dest).err_ctx(|| (header, "copy file"))?;
             }
+
+            if lib.export_symbols != ExportSymbols::All {
+                exports::write_export_header(&lib.target.name, target_dir.as_ref())?;
+            }
         }
 
         Ok(())
     }
 }
+
+/// Reports a test which failed to build
+fn test_build_failure(name: String, error: Error) -> TestResult {
+    println!("      \u{1B}[31;1mError\u{1B}[0m: {}", error);
+    TestResult {
+        name,
+        outcome: TestOutcome::BuildFailed(error.to_string()),
+        duration: Default::default(),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use super::{CompilerFamily, Error, HeaderExtractor, Instrumentation, Languages, LibraryType, Linkage, OsSpec, Project, ProfileError, TestOptions, Version, fuse_ld_option, get_file_mtime, header_to_unit};
+
+    fn load(manifest: &str) -> Result<Project, Error> {
+        let mut project = toml::from_str::<Project>(manifest).unwrap();
+        project.init_default_profiles()?;
+        Ok(project)
+    }
+
+    fn paths(options: &[&str]) -> Vec<PathBuf> {
+        options.iter().map(Into::into).collect()
+    }
+
+    /// Writes files of a project into a fresh temporary directory
+    fn write_project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gocar-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    /// Rewrites the file making sure its modification time changes
+    fn touch(file: &Path, content: &str) {
+        std::thread::sleep(Duration::from_millis(50));
+        std::fs::write(file, content).unwrap();
+    }
+
+    #[test]
+    fn inherits_builtin() {
+        let project = load(r#"
+            add_compile_options = ["-Wall"]
+
+            [profiles.asan]
+            inherits = "debug"
+            c_compiler = "clang"
+            compile_options = ["-fsanitize=address"]
+        "#).unwrap();
+
+        let asan = &project.profiles["asan"];
+        assert_eq!(asan.compile_options.common, paths(&["-g", "-DDEBUG", "-fsanitize=address", "-Wall"]));
+        assert_eq!(asan.compiler(project.languages.get("c").unwrap()), Path::new("clang"));
+        assert_eq!(project.profiles["debug"].compile_options.common, paths(&["-g", "-DDEBUG", "-Wall"]));
+    }
+
+    #[test]
+    fn inherits_transitively() {
+        let project = load(r#"
+            [profile.a]
+            inherits = "release"
+            cpp_compiler = "clang++"
+            link_options = ["-la"]
+
+            [profile.b]
+            inherits = "a"
+            link_options = ["-lb"]
+        "#).unwrap();
+
+        let b = &project.profiles["b"];
+        assert_eq!(b.compile_options.common, paths(&["-O2"]));
+        assert_eq!(b.link_options, paths(&["-la", "-lb"]));
+        assert_eq!(b.compiler(project.languages.get("cpp").unwrap()), Path::new("clang++"));
+    }
+
+    #[test]
+    fn target_overrides() {
+        let project = load(r#"
+            [profiles.debug.target.foo]
+            compile_options = ["-O3"]
+
+            [profiles.fast]
+            inherits = "debug"
+
+            [profiles.fast.target.foo]
+            c_compile_options = ["-march=native"]
+            link_options = ["-lm"]
+        "#).unwrap();
+
+        let fast = &project.profiles["fast"];
+        let (c, cpp) = (project.languages.get("c").unwrap(), project.languages.get("cpp").unwrap());
+        assert_eq!(fast.target_compile_options("foo".as_ref(), c).collect::<Vec<_>>(), paths(&["-O3", "-march=native"]).iter().collect::<Vec<_>>());
+        assert_eq!(fast.target_compile_options("foo".as_ref(), cpp).collect::<Vec<_>>(), paths(&["-O3"]).iter().collect::<Vec<_>>());
+        assert_eq!(fast.target_link_options("foo".as_ref()).collect::<Vec<_>>(), paths(&["-lm"]).iter().collect::<Vec<_>>());
+        assert_eq!(fast.target_compile_options("bar".as_ref(), c).count(), 0);
+    }
+
+    #[test]
+    fn link_and_archive_options() {
+        let project = load(r#"
+            add_archive_options = ["--plugin=liblto_plugin.so"]
+
+            [profiles.debug]
+            link_options = ["-lm"]
+            archive_options = ["-U"]
+
+            [profiles.debug.target.foo]
+            link_options = ["-Wl,--as-needed"]
+            archive_options = ["-D"]
+
+            [[lib]]
+            name = "foo"
+            root_files = ["src/foo.c"]
+            link_options = ["-lpthread"]
+            archive_options = ["-P"]
+        "#).unwrap();
+
+        let (debug, foo, os) = (&project.profiles["debug"], &project.lib[0].target, OsSpec::linux());
+        assert_eq!(foo.link_options(debug, &os.dynamic_lib_spec).collect::<Vec<_>>(), paths(&["-shared", "-lm", "-Wl,--as-needed", "-lpthread"]).iter().collect::<Vec<_>>());
+        assert_eq!(foo.archive_options(debug).collect::<Vec<_>>(), paths(&["-U", "--plugin=liblto_plugin.so", "-D", "-P"]).iter().collect::<Vec<_>>());
+        assert_eq!(foo.link_options(&project.profiles["release"], &os.static_lib_spec).collect::<Vec<_>>(), paths(&["-lpthread"]).iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn assembly() {
+        let project = load(r#"
+            add_asm_compile_options = ["-DASM"]
+
+            [profiles.cross]
+            inherits = "debug"
+            c_compiler = "arm-none-eabi-gcc"
+        "#).unwrap();
+
+        let cross = &project.profiles["cross"];
+        let asm = project.languages.determine_from_file("src/fast.s").unwrap();
+        assert_eq!(project.languages.determine_from_file("src/fast.S").unwrap().name, "asm");
+        assert_eq!(cross.compiler(asm), Path::new("arm-none-eabi-gcc"));
+        assert_eq!(cross.compile_options.all(asm).collect::<Vec<_>>(), paths(&["-g", "-DDEBUG", "-DASM"]).iter().collect::<Vec<_>>());
+        assert_eq!(cross.compile_options.all(project.languages.get("c").unwrap()).count(), 2);
+    }
+
+    #[test]
+    fn custom_language() {
+        let project = load(r#"
+            add_objc_compile_options = ["-fobjc-arc"]
+
+            [language.objc]
+            extensions = ["m", "mm"]
+            compiler = "clang"
+
+            [profiles.debug]
+            objc_compiler = "gcc"
+            objc_compile_options = ["-DOBJC"]
+        "#).unwrap();
+
+        let objc = project.languages.determine_from_file("src/main.mm").unwrap();
+        let debug = &project.profiles["debug"];
+        assert_eq!(debug.compiler(objc), Path::new("gcc"));
+        assert_eq!(project.profiles["release"].compiler(objc), Path::new("clang"));
+        assert_eq!(debug.compile_options.all(objc).collect::<Vec<_>>(), paths(&["-DOBJC", "-fobjc-arc"]).iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn header_extensions() {
+        let header_extensions = super::default_header_extensions();
+        let output = &b"foo.o: src/foo.cc src/foo.hh \\\n include/bar.hxx src/baz.ipp \\\n src/qux.h\n"[..];
+        let headers = HeaderExtractor::new(output, &header_extensions).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(headers, paths(&["src/foo.hh", "include/bar.hxx", "src/baz.ipp", "src/qux.h"]));
+    }
+
+    #[test]
+    fn header_units() {
+        let dir = std::env::temp_dir().join(format!("gocar-header-units-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in &["google.h", "google.cc", "both.hpp", "both.c", "both.cpp", "none.hxx"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let languages = Languages::default();
+        assert_eq!(header_to_unit(dir.join("google.h"), &languages, None).unwrap(), Some(dir.join("google.cc")));
+        assert_eq!(header_to_unit(dir.join("none.hxx"), &languages, None).unwrap(), None);
+        let ambiguous = header_to_unit(dir.join("both.hpp"), &languages, None).unwrap_err();
+        assert_eq!(ambiguous.units, [dir.join("both.c"), dir.join("both.cpp")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unit_tests_of_dynamic_library() {
+        let dir = write_project("unit-dynamic", &[
+            // Linking with `--as-needed` would drop the shared library from the runner and hide the problem
+            ("Gocar.toml", "[[lib]]\nname = \"counter\"\nroot_files = [\"src/counter.c\"]\nlink_options = [\"-Wl,--no-as-needed\"]\n"),
+            ("src/counter.c", "#include \"gocar_test.h\"\nint counter_inits = 0;\n__attribute__((constructor)) static void count_init(void) { counter_inits++; }\nint counter_get(void) { return counter_inits; }\nGOCAR_TEST(initialized_once) { GOCAR_ASSERT(counter_get() == 1); }\n"),
+        ]);
+        let project = Project::load_from_dir(&dir).unwrap();
+        let options = TestOptions { jobs: 1, ..Default::default() };
+        let report = project.test(dir.join("target"), &dir, "debug", Linkage::Dynamic, &Instrumentation::default(), &options).unwrap();
+
+        // The runner contains the library itself, so the shared library would initialize it again
+        assert_eq!(report.passed(), 1);
+        assert_eq!(report.failed(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relinks_programs_with_rebuilt_libraries() {
+        let dir = write_project("relink", &[
+            ("Gocar.toml", "[[lib]]\nname = \"calc\"\nroot_files = [\"src/calc.c\"]\npublic_headers = [\"calc.h\"]\n"),
+            ("src/calc.h", "int answer(void);\n"),
+            ("src/calc.c", "#include \"calc.h\"\nint answer(void) { return 41; }\n"),
+            ("examples/show.c", "#include \"calc.h\"\nint main(void) { return answer() != 42; }\n"),
+        ]);
+        let project = Project::load_from_dir(&dir).unwrap();
+        let target_dir = dir.join("target");
+        let build = || project.build_examples(&target_dir, &dir, "debug", Linkage::Static, &Instrumentation::default(), None).unwrap();
+
+        let (_, example) = build().remove(0);
+        let built = get_file_mtime(&example).unwrap().unwrap();
+        build();
+        assert_eq!(get_file_mtime(&example).unwrap(), Some(built));
+
+        touch(&dir.join("src/calc.c"), "#include \"calc.h\"\nint answer(void) { return 42; }\n");
+        build();
+        assert!(get_file_mtime(&example).unwrap().unwrap() > built);
+        assert!(std::process::Command::new(&example).status().unwrap().success());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn links_generated_units() {
+        let dir = write_project("generated", &[
+            ("Gocar.toml", "[[bin]]\nname = \"app\"\nroot_files = [\"src/main.c\"]\n\n[[generator]]\ninput = \"data/*.in\"\ncommand = [\"cp\", \"{input}\", \"{out_dir}/{stem}.c\"]\noutputs = [\"{stem}.c\"]\n"),
+            ("src/main.c", "int answer(void);\nint main(void) { return answer() != 42; }\n"),
+            ("data/answer.in", "int answer(void) { return 42; }\n"),
+        ]);
+        let project = Project::load_from_dir(&dir).unwrap();
+        let target_dir = dir.join("target");
+        project.build(&target_dir, &dir, "debug", Linkage::Static, &Instrumentation::default()).unwrap();
+        assert!(std::process::Command::new(target_dir.join("app")).status().unwrap().success());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn modules_of_linked_libraries() {
+        // GCC older than 14 can't print P1689, so a minimal scanner logging its runs is used
+        let scanner = r#"for input; do :; done
+echo "$input" >> "$(dirname "$0")/scans.log"
+provides=$(sed -n 's/^export module \([A-Za-z0-9_.:]*\);.*/{"logical-name": "\1"}/p' "$input" | paste -sd, -)
+requires=$(sed -n 's/^import \([A-Za-z0-9_.:]*\);.*/{"logical-name": "\1"}/p' "$input" | paste -sd, -)
+printf '{"rules": [{"provides": [%s], "requires": [%s]}]}\n' "$provides" "$requires"
+"#;
+        let dir = write_project("modules", &[
+            ("scan.sh", scanner),
+            ("src/geometry.cppm", "export module geometry;\nexport int area(int width, int height) { return width * height; }\n"),
+            ("examples/show.cpp", "import geometry;\nint main() { return area(2, 3) != 6; }\n"),
+        ]);
+        let language = |name: &str, extensions: &str, extra: &str| format!("[language.{}]\nextensions = [{}]\ncompiler = \"c++\"\noptions_key = \"cpp\"\nlink_precedence = 1\nmodule_scan = [\"sh\", \"{}\", \"{{input}}\"]\nmodule_options = [\"-fmodules-ts\", \"-fmodule-mapper={{mapper}}\"]\n{}\n", name, extensions, dir.join("scan.sh").display(), extra);
+        let manifest = format!("modules = true\nadd_cpp_compile_options = [\"-std=c++20\"]\n\n[[lib]]\nname = \"geometry\"\nroot_files = [\"src/geometry.cppm\"]\n\n{}{}", language("cpp", "\"cpp\"", ""), language("cppm", "\"cppm\"", "required_options = [\"-x\", \"c++\"]\nmodule_interfaces = true"));
+        std::fs::write(dir.join("Gocar.toml"), manifest).unwrap();
+        let project = Project::load_from_dir(&dir).unwrap();
+        let target_dir = dir.join("target");
+        let build = || project.build_examples(&target_dir, &dir, "debug", Linkage::Static, &Instrumentation::default(), None).unwrap();
+        let scans = || std::fs::read_to_string(dir.join("scans.log")).unwrap().lines().count();
+
+        let (_, example) = build().remove(0);
+        assert!(std::process::Command::new(&example).status().unwrap().success());
+        // The interface is only compiled into the library
+        let objects = std::process::Command::new("find").arg(target_dir.join("examples")).args(["-name", "geometry.o"]).output().unwrap().stdout;
+        assert!(objects.is_empty());
+
+        let scanned = scans();
+        build();
+        assert_eq!(scans(), scanned);
+
+        touch(&dir.join("examples/show.cpp"), "import geometry;\nint main() { return area(3, 2) != 6; }\n");
+        build();
+        assert_eq!(scans(), scanned + 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn precompiled_header_without_source() {
+        let dir = write_project("pch", &[
+            ("Gocar.toml", "[[bin]]\nname = \"app\"\nroot_files = [\"src/main.cpp\"]\nprecompiled_header = \"src/pch.hpp\"\n"),
+            ("src/pch.hpp", "#ifndef PCH_HPP\n#define PCH_HPP\n#include <vector>\ninline int answer() { return 42; }\n#endif\n"),
+            ("src/main.cpp", "#include \"pch.hpp\"\nint main() { return std::vector<int>(1, answer())[0] != 42; }\n"),
+        ]);
+        let project = Project::load_from_dir(&dir).unwrap();
+        let target_dir = dir.join("target");
+        project.build(&target_dir, &dir, "debug", Linkage::Static, &Instrumentation::default()).unwrap();
+        assert!(std::process::Command::new(target_dir.join("app")).status().unwrap().success());
+
+        // The compiler falls back to the header next to the precompiled one if it can't use it
+        let pch_dir = target_dir.join("pch/app/cpp");
+        assert!(pch_dir.join("pch.hpp.gch").is_file());
+        let forwarding = std::fs::read_to_string(pch_dir.join("pch.hpp")).unwrap();
+        assert_eq!(forwarding, format!("#include \"{}\"\n", dir.join("src/pch.hpp").canonicalize().unwrap().display()));
+        std::fs::remove_file(pch_dir.join("pch.hpp.gch")).unwrap();
+        let status = std::process::Command::new("c++").arg("-include").arg(pch_dir.join("pch.hpp")).arg("-fsyntax-only").arg(dir.join("src/main.cpp")).status().unwrap();
+        assert!(status.success());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compile_cache_with_precompiled_header() {
+        let dir = write_project("cache-pch", &[
+            ("Gocar.toml", "[[bin]]\nname = \"app\"\nroot_files = [\"src/main.cpp\"]\nprecompiled_header = \"src/pch.hpp\"\n\n[profiles.debug]\ncompile_cache = true\ncompile_cache_dir = \"cache\"\n"),
+            ("src/pch.hpp", "#ifndef PCH_HPP\n#define PCH_HPP\ninline int answer() { return 42; }\n#endif\n"),
+            ("src/main.cpp", "#include \"pch.hpp\"\nint main() { return answer() != 42; }\n"),
+        ]);
+        let cache_dir = dir.join("cache");
+        let cached_objects = || {
+            let mut count = 0;
+            for entry in std::fs::read_dir(&cache_dir).unwrap() {
+                count += std::fs::read_dir(entry.unwrap().path()).unwrap().count();
+            }
+            count
+        };
+        let project = Project::load_from_dir(&dir).unwrap();
+        let build = |target_dir: &Path| {
+            project.build(target_dir, &dir, "debug", Linkage::Static, &Instrumentation::default()).unwrap();
+            std::process::Command::new(target_dir.join("app")).status().unwrap().success()
+        };
+
+        assert!(build(&dir.join("target")));
+        assert_eq!(cached_objects(), 1);
+
+        // The object is restored from the cache after cleaning, while the header is precompiled again
+        std::fs::remove_dir_all(dir.join("target")).unwrap();
+        assert!(build(&dir.join("target")));
+        assert_eq!(cached_objects(), 1);
+
+        // but it depends on the header itself
+        touch(&dir.join("src/pch.hpp"), "#ifndef PCH_HPP\n#define PCH_HPP\ninline int answer() { return 43; }\n#endif\n");
+        assert!(!build(&dir.join("target")));
+        assert_eq!(cached_objects(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn linkage() {
+        assert_eq!("static".parse::<Linkage>().unwrap(), Linkage::Static);
+        assert_eq!("dynamic".parse::<Linkage>().unwrap(), Linkage::Dynamic);
+        assert_eq!("both".parse::<Linkage>().unwrap(), Linkage::Both);
+        assert_eq!("shared".parse::<Linkage>().unwrap_err().to_string(), "unknown linkage shared, expected one of static, dynamic, both");
+    }
+
+    #[test]
+    fn disallowed_linkage() {
+        let project = load(r#"
+            [[lib]]
+            name = "plugin"
+            root_files = ["src/plugin.c"]
+            disallow_static = true
+
+            [[lib]]
+            name = "none"
+            root_files = ["src/none.c"]
+            disallow_static = true
+            disallow_dynamic = true
+        "#).unwrap();
+
+        let (plugin, none) = (&project.lib[0], &project.lib[1]);
+        assert_eq!(plugin.library_types(Linkage::Both).unwrap(), [LibraryType::Dynamic]);
+        assert_eq!(plugin.library_types(Linkage::Dynamic).unwrap(), [LibraryType::Dynamic]);
+        assert_eq!(plugin.library_types(Linkage::Static).unwrap_err().to_string(), "library plugin disallows static linkage");
+        assert_eq!(none.library_types(Linkage::Dynamic).unwrap_err().to_string(), "library none disallows dynamic linkage");
+        assert_eq!(none.library_types(Linkage::Both).unwrap_err().to_string(), "library none disallows both static and dynamic linkage");
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!("1.2.3".parse::<Version>().unwrap(), Version { major: 1, minor: 2, patch: 3, });
+        assert_eq!("4".parse::<Version>().unwrap().to_string(), "4.0.0");
+        assert!("1.2.3.4".parse::<Version>().is_err());
+        assert!("1.x".parse::<Version>().is_err());
+        assert!("".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn compiler_family_overrides() {
+        let project = load(r#"
+            [profiles.debug.compiler_family.clang]
+            compile_options = ["-Weverything"]
+
+            [profiles.strict]
+            inherits = "debug"
+            [profiles.strict.compiler_family.clang]
+            compile_options = ["-Werror"]
+        "#).unwrap();
+
+        let strict = &project.profiles["strict"];
+        let c = project.languages.get("c").unwrap();
+        assert_eq!(strict.family_compile_options(CompilerFamily::Clang, c).collect::<Vec<_>>(), paths(&["-Weverything", "-Werror"]).iter().collect::<Vec<_>>());
+        assert_eq!(strict.family_compile_options(CompilerFamily::Gcc, c).count(), 0);
+    }
+
+    #[test]
+    fn fuse_ld() {
+        assert_eq!(fuse_ld_option(Path::new("lld"), CompilerFamily::Gcc).unwrap(), "-fuse-ld=lld");
+        assert_eq!(fuse_ld_option(Path::new("ld.gold"), CompilerFamily::Gcc).unwrap(), "-fuse-ld=gold");
+        assert_eq!(fuse_ld_option(Path::new("/usr/bin/ld.mold"), CompilerFamily::Clang).unwrap(), "-fuse-ld=mold");
+        assert_eq!(fuse_ld_option(Path::new("/usr/bin/ld"), CompilerFamily::Gcc), None);
+        assert_eq!(fuse_ld_option(Path::new("/usr/bin/x86_64-linux-gnu-ld"), CompilerFamily::Clang).unwrap(), "--ld-path=/usr/bin/x86_64-linux-gnu-ld");
+        assert_eq!(fuse_ld_option(Path::new("/usr/bin/x86_64-linux-gnu-ld"), CompilerFamily::Gcc), None);
+        assert_eq!(fuse_ld_option(Path::new("x86_64-linux-gnu-ld"), CompilerFamily::Clang), None);
+    }
+
+    #[test]
+    fn unknown_language_keys() {
+        assert!(load("cpp_compiler = \"clang++\"\nadd_asm_compile_options = [\"-g\"]\n[[bin]]\nname = \"app\"\nroot_files = []\ncpp_compile_options = [\"-O2\"]\n").is_ok());
+
+        let error = load("[[bin]]\nname = \"app\"\nroot_files = []\ncxx_compile_options = [\"-O2\"]\n").err().unwrap();
+        assert_eq!(error.to_string(), "cxx_compile_options doesn't match options key of any language");
+        let error = load("add_objc_compile_options = [\"-g\"]\n").err().unwrap();
+        assert_eq!(error.to_string(), "add_objc_compile_options doesn't match options key of any language");
+        let error = load("[profiles.debug.target.app]\ncc_compile_options = [\"-O2\"]\n").err().unwrap();
+        assert_eq!(error.to_string(), "cc_compile_options doesn't match options key of any language");
+        assert!(load("[language.objc]\nextensions = [\"m\"]\ncompiler = \"clang\"\n\n[profiles.debug]\nobjc_compiler = \"clang\"\n").is_ok());
+    }
+
+    #[test]
+    fn unknown_parent() {
+        match load("[profiles.foo]\ninherits = \"bar\"\n") {
+            Err(Error::Profile(ProfileError::UnknownParent { profile, parent })) => {
+                assert_eq!(profile, "foo");
+                assert_eq!(parent, "bar");
+            },
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn inheritance_cycle() {
+        match load("[profiles.a]\ninherits = \"b\"\n[profiles.b]\ninherits = \"a\"\n") {
+            Err(Error::Profile(ProfileError::InheritanceCycle(chain))) => assert_eq!(chain, ["a", "b", "a"]),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
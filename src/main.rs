@@ -11,111 +11,170 @@ fn load_config() -> gocar::Project {
         .unwrap();
 
     let mut config = toml::from_slice::<gocar::Project>(&config).unwrap();
-    config.init_default_profiles();
+    if let Err(err) = config.init_default_profiles() {
+        fail(err);
+    }
     //println!("Config: {:?}", config);
     config
 }
 
-fn build(profile: &str) {
+fn build(profile: &str, linkage: gocar::Linkage, instrumentation: &gocar::Instrumentation, examples: bool) {
     let config = load_config();
-    let target = AsRef::<std::path::Path>::as_ref("target").join(profile);
+    let target = AsRef::<std::path::Path>::as_ref("target").join(instrumentation.profile_dir_name(profile));
     let current_dir = std::env::current_dir().expect("Invalid current working directory");
 
     std::fs::create_dir_all(&target).unwrap();
-    match config.build(&target, &current_dir, profile, gocar::LibraryType::Static) {
-        Ok(()) => (),
-        Err(gocar::Error::Command(err)) => println!("      \u{1B}[31;1mError\u{1B}[0m: {}", err),
-        Err(gocar::Error::Filesystem(err)) => println!("      \u{1B}[31;1mError\u{1B}[0m: {}", err),
-        Err(gocar::Error::InvalidProfileName) => println!("      \u{1B}[31;1mError\u{1B}[0m: invalid profile name"),
+    if let Err(err) = config.build(&target, &current_dir, profile, linkage, instrumentation) {
+        fail(err);
+    }
+
+    if examples {
+        if let Err(err) = config.build_examples(&target, &current_dir, profile, linkage, instrumentation, None) {
+            fail(err);
+        }
     }
 }
 
-fn test(profile_name: &str) {
+fn run_example(profile: &str, linkage: gocar::Linkage, instrumentation: &gocar::Instrumentation, name: &str, args: &[String]) -> ! {
     let config = load_config();
+    let target = AsRef::<std::path::Path>::as_ref("target").join(instrumentation.profile_dir_name(profile));
+    let current_dir = std::env::current_dir().expect("Invalid current working directory");
 
-    let headers_only = config.headers_only.iter().map(|path| path.canonicalize()).collect::<Result<_, _>>().expect("Failed to canonicalize headers_only");
-    let mut target = AsRef::<std::path::Path>::as_ref("target").join(profile_name);
-    target.push("integration_tests");
-    let profile = config.profiles.get(profile_name).expect("unknown profile");
-    //println!("Testing with profile: {:?}", profile);
+    std::fs::create_dir_all(&target).unwrap();
+    let examples = config.build_examples(&target, &current_dir, profile, linkage, instrumentation, Some(name)).unwrap_or_else(|err| fail(err));
+    let (_, path) = &examples[0];
 
-    let mut test_count = 0;
-    let mut fail_count = 0;
+    println!("     \u{1B}[32;1mRunning\u{1B}[0m {:?}", path);
+    match std::process::Command::new(path).args(args).status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(101)),
+        Err(err) => fail(format!("failed to run {}: {}", path.display(), err)),
+    }
+}
 
+fn test(profile_name: &str, linkage: gocar::Linkage, instrumentation: &gocar::Instrumentation, options: &gocar::TestOptions) {
+    let config = load_config();
+    let target = AsRef::<std::path::Path>::as_ref("target").join(instrumentation.profile_dir_name(profile_name));
     let current_dir = std::env::current_dir().expect("Invalid current working directory");
 
     std::fs::create_dir_all(&target).unwrap();
 
-    let (include_dir, lib_dirs, libs) = config.build_dependencies(&target, &current_dir, profile_name, gocar::LibraryType::Static).unwrap();
-
-    for test in std::fs::read_dir("tests").unwrap().map(Result::unwrap).map(|e| e.path()) {
-        let extension_is_valid = if let Some(extension) = test.extension() {
-            extension == "c" || extension == "cpp"
-        } else {
-            continue;
-        };
-
-        let test_name: std::path::PathBuf = test.file_stem().unwrap().into();
-        if extension_is_valid {
-            let binary = gocar::Binary {
-                target: gocar::Target {
-                    name: test_name.clone(),
-                    root_files: std::iter::once(test).collect(),
-                    compile_options: gocar::CompileOptions::debug(),
-                    link_options: Vec::new(),
-                    ignore_files: Default::default(),
-                    _phantom: Default::default(),
-                }
-            };
-
-            test_count += 1;
-
-            let env = gocar::BuildEnv {
-                target_dir: &target,
-                include_dir: &include_dir,
-                include_dirs: &[],
-                lib_dirs: &lib_dirs,
-                libs: &libs,
-                profile,
-                strip_prefix: &current_dir,
-                project_dir: &current_dir,
-                project: &config,
-                headers_only: &headers_only,
-                os: gocar::OsSpec::linux(),
-            };
-
-            binary.build(&env).unwrap();
-            let test_binary = target.join(&test_name);
-            println!("     \u{1B}[32;1mRunning\u{1B}[0m {:?}", test_binary);
-
-            if !std::process::Command::new(&test_binary)
-                .spawn().unwrap()
-                .wait().unwrap()
-                .success() {
-                    fail_count += 1;
-                    println!("      \u{1B}[31;1mFailed\u{1B}[0m {:?}", test_binary);
-            }
-        }
+    if instrumentation.coverage() {
+        gocar::clear_coverage_data(&target).unwrap();
     }
 
-    println!("test result: {}. total: {}; passed: {}; failed: {}", if fail_count == 0 { "\u{1B}[32mok\u{1B}[0m" } else { "\u{1B}[31mFAILED\u{1B}[0m" }, test_count, test_count - fail_count, fail_count);
+    let report = match config.test(&target, &current_dir, profile_name, linkage, instrumentation, options) {
+        Ok(report) => report,
+        Err(err) => fail(err),
+    };
+
+    if instrumentation.coverage() {
+        print_coverage(&target, &current_dir);
+    }
+
+    if !report.success() {
+        std::process::exit(101);
+    }
 }
 
+fn bench(profile_name: &str, linkage: gocar::Linkage, filter: Option<&str>) {
+    let config = load_config();
+    let target = AsRef::<std::path::Path>::as_ref("target").join(profile_name);
+    let current_dir = std::env::current_dir().expect("Invalid current working directory");
+
+    std::fs::create_dir_all(&target).unwrap();
+    if let Err(err) = config.bench(&target, &current_dir, profile_name, linkage, filter) {
+        fail(err);
+    }
+}
+
+fn fail<E: std::fmt::Display>(error: E) -> ! {
+    println!("      \u{1B}[31;1mError\u{1B}[0m: {}", error);
+    std::process::exit(1);
+}
+
+fn print_coverage(target: &std::path::Path, project_dir: &std::path::Path) {
+    let coverage = match gocar::collect_coverage(target, project_dir) {
+        Ok(coverage) => coverage,
+        Err(err) => {
+            println!("      \u{1B}[31;1mError\u{1B}[0m: failed to collect coverage: {}", err);
+            return;
+        },
+    };
+
+    let mut total_lines = 0;
+    let mut total_executed = 0;
+    println!("coverage:");
+    for file in &coverage {
+        total_lines += file.lines;
+        total_executed += file.executed;
+        println!("  {:>6.2}% {:>5}/{:<5} {}", percent(file.executed, file.lines), file.executed, file.lines, file.source.strip_prefix(project_dir).unwrap_or(&file.source).display());
+    }
+    println!("  {:>6.2}% {:>5}/{:<5} total", percent(total_executed, total_lines), total_executed, total_lines);
+}
+
+fn percent(executed: u64, lines: u64) -> f64 {
+    if lines == 0 {
+        100.0
+    } else {
+        executed as f64 * 100.0 / lines as f64
+    }
+}
+
+const USAGE: &str = "Usage: gocar (build [--examples] | run --example NAME [-- ARGS...] | test [FILTER] | bench [FILTER]) [--release | --profile NAME] [--linkage static|dynamic|both] [--sanitize SANITIZER[,SANITIZER...]] [--coverage] [--jobs N] [--timeout SECS] [--no-fail-fast] [--format junit|json]";
+
 fn main() {
     let mut args = std::env::args();
     args.next().expect("Not even zeroth argument given");
-    let action = args.next().expect("Usage: gocar (build [--release] | run [--release] | test)");
-
-    let profile = if let Some("--release") = args.next().as_ref().map(AsRef::as_ref) {
-        "release"
-    } else {
-        "debug"
-    };
+    let action = args.next().expect(USAGE);
+    let is_test = action == "test";
+    let is_bench = action == "bench";
+    let is_run = action == "run";
+
+    // Benchmarks are useless without optimizations
+    let mut profile = if is_bench { "release" } else { "debug" }.to_owned();
+    let mut linkage = gocar::Linkage::Static;
+    let mut instrumentation = gocar::Instrumentation::default();
+    let mut test_options = gocar::TestOptions::default();
+    let mut examples = false;
+    let mut example = None;
+    let mut run_args = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--release" => profile = "release".to_owned(),
+            "--profile" => profile = args.next().expect("Missing profile name"),
+            "--linkage" => linkage = args.next().expect("Missing linkage").parse().unwrap_or_else(|err| fail(err)),
+            // Sanitizers would distort measurements of benchmarks
+            "--sanitize" if is_bench => fail("benchmarks can't be built with sanitizers"),
+            "--sanitize" => {
+                for sanitizer in args.next().expect("Missing sanitizer name").split(',') {
+                    let sanitizer = sanitizer.parse().unwrap_or_else(|err| fail(err));
+                    instrumentation.add_sanitizer(sanitizer).unwrap_or_else(|err| fail(err));
+                }
+            },
+            "--examples" if action == "build" => examples = true,
+            "--example" if is_run => example = Some(args.next().expect("Missing example name")),
+            "--" if is_run => run_args.extend(args.by_ref()),
+            "--coverage" if is_test => instrumentation.enable_coverage(),
+            "--jobs" if is_test => test_options.jobs = args.next().expect("Missing number of jobs").parse().unwrap_or_else(|err| fail(err)),
+            "--timeout" if is_test => {
+                let seconds = args.next().expect("Missing timeout").parse().unwrap_or_else(|err| fail(err));
+                test_options.timeout = Some(std::time::Duration::from_secs(seconds));
+            },
+            "--no-fail-fast" if is_test => test_options.fail_fast = false,
+            "--format" if is_test => test_options.format = Some(args.next().expect("Missing report format").parse().unwrap_or_else(|err| fail(err))),
+            filter if (is_test || is_bench) && !filter.starts_with('-') && test_options.filter.is_none() => test_options.filter = Some(filter.to_owned()),
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
 
     match action.as_ref() {
-        "build" => build(profile),
-        "run" => unimplemented!(),
-        "test" => test(profile),
+        "build" => build(&profile, linkage, &instrumentation, examples),
+        "run" => match example {
+            Some(example) => run_example(&profile, linkage, &instrumentation, &example, &run_args),
+            None => fail("running binaries is not implemented yet, use --example NAME to run an example"),
+        },
+        "test" => test(&profile, linkage, &instrumentation, &test_options),
+        "bench" => bench(&profile, linkage, test_options.filter.as_deref()),
         _ => panic!("Unknown action: {}", action),
     }
 }
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use super::{BuildEnv, Command, FsResult, GocarResult, Language, ResultExt, canonicalize, create_dir_all, get_file_mtime, is_older, objs, scan_c_files, unit_to_obj, write_if_changed};
+
+/// Error in C++20 module dependencies
+#[derive(Debug)]
+pub enum ModuleError {
+    /// The scanner printed something else than P1689 dependency information
+    InvalidScan { unit: PathBuf, error: serde_json::Error, },
+    /// No unit in the project provides imported module
+    UnknownModule { unit: PathBuf, module: String, },
+    /// Module is provided by more than one unit
+    DuplicateModule { module: String, units: [PathBuf; 2], },
+    /// Modules import each other
+    Cycle { unit: PathBuf, },
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModuleError::InvalidScan { unit, error } => write!(f, "invalid module dependencies of {}: {}", unit.display(), error),
+            ModuleError::UnknownModule { unit, module } => write!(f, "module {} imported by {} not found", module, unit.display()),
+            ModuleError::DuplicateModule { module, units: [first, second] } => write!(f, "module {} is provided by both {} and {}", module, first.display(), second.display()),
+            ModuleError::Cycle { unit } => write!(f, "cyclic module imports involving {}", unit.display()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct P1689 {
+    #[serde(default)]
+    rules: Vec<P1689Rule>,
+}
+
+#[derive(Deserialize)]
+struct P1689Rule {
+    #[serde(default)]
+    provides: Vec<P1689Module>,
+    #[serde(default)]
+    requires: Vec<P1689Module>,
+}
+
+#[derive(Deserialize)]
+struct P1689Module {
+    #[serde(rename = "logical-name")]
+    logical_name: String,
+}
+
+/// Modules provided and required by a single unit
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnitModules {
+    pub provides: Vec<String>,
+    pub requires: Vec<String>,
+}
+
+impl UnitModules {
+    fn parse(output: &[u8]) -> serde_json::Result<Self> {
+        let mut modules = UnitModules::default();
+        for rule in serde_json::from_slice::<P1689>(output)?.rules {
+            modules.provides.extend(rule.provides.into_iter().map(|module| module.logical_name));
+            modules.requires.extend(rule.requires.into_iter().map(|module| module.logical_name));
+        }
+        Ok(modules)
+    }
+}
+
+/// Runs the scanner of the language on the unit.
+fn scan_unit(unit: &Path, object: &Path, language: &Language, compiler: &Path, options: &[OsString]) -> GocarResult<UnitModules> {
+    let (program, args) = match language.module_scan.split_first() {
+        Some(command) => command,
+        None => return Ok(Default::default()),
+    };
+
+    let substitute = |arg: &str| -> OsString {
+        arg.replace("{compiler}", &compiler.to_string_lossy())
+            .replace("{input}", &unit.to_string_lossy())
+            .replace("{object}", &object.to_string_lossy())
+            .into()
+    };
+
+    let args = args.iter().flat_map(|arg| if arg == "{options}" { options.to_vec() } else { vec![substitute(arg)] });
+    let output = Command::new(substitute(program)).args(args).output()?;
+    UnitModules::parse(&output).map_err(|error| ModuleError::InvalidScan { unit: unit.to_owned(), error, }.into())
+}
+
+/// Results of previous scans of a target, stored as JSON
+///
+/// A result is reused if neither the unit nor its dependencies changed since it was stored.
+struct ScanCache {
+    path: PathBuf,
+    stored: Option<SystemTime>,
+    units: HashMap<PathBuf, UnitModules>,
+    modified: bool,
+}
+
+impl ScanCache {
+    fn load(path: PathBuf) -> FsResult<Self> {
+        let stored = get_file_mtime(&path)?;
+        // An unreadable cache only means scanning again
+        let units = std::fs::read(&path)
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+
+        Ok(ScanCache { path, stored, units, modified: false, })
+    }
+
+    fn get<F: FnOnce() -> GocarResult<UnitModules>>(&mut self, unit: &Path, dependencies: &[PathBuf], scan: F) -> GocarResult<UnitModules> {
+        if let Some(stored) = self.stored {
+            if !is_older(stored, std::iter::once(unit).chain(dependencies.iter().map(AsRef::as_ref)))? {
+                if let Some(modules) = self.units.remove(unit) {
+                    return Ok(modules);
+                }
+            }
+        }
+
+        self.modified = true;
+        scan()
+    }
+
+    /// Stores results of all scanned units, the file is rewritten even if they're the same to
+    /// record the time of the scan.
+    fn store(&self, units: &HashMap<PathBuf, UnitModules>) -> FsResult<()> {
+        if !self.modified && self.units.is_empty() {
+            return Ok(());
+        }
+
+        let content = serde_json::to_vec(units).expect("Serializing modules can't fail");
+        std::fs::write(&self.path, content).err_ctx(|| (self.path.clone(), "write file"))
+    }
+}
+
+/// Finds module interface units anywhere in the project except hidden directories and `target`.
+fn find_interface_units(env: &BuildEnv) -> FsResult<Vec<PathBuf>> {
+    fn find(dir: &Path, env: &BuildEnv, units: &mut Vec<PathBuf>) -> FsResult<()> {
+        for entry in std::fs::read_dir(dir).err_ctx(|| (dir.to_owned(), "read directory"))? {
+            let path = entry.err_ctx(|| (dir.to_owned(), "read directory"))?.path();
+            let hidden = path.file_name().map_or(false, |name| name.to_string_lossy().starts_with('.'));
+            if hidden || path == env.project_dir.join("target") {
+                continue;
+            }
+
+            if path.is_dir() {
+                find(&path, env, units)?;
+            } else if env.project.languages.determine_from_file(&path).map_or(false, |language| language.module_interfaces) {
+                units.push(canonicalize(path)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut units = Vec::new();
+    find(env.project_dir, env, &mut units)?;
+    units.sort();
+    Ok(units)
+}
+
+/// Module dependencies of units of a single target
+pub struct Modules {
+    units: HashMap<PathBuf, UnitModules>,
+    bmis: BTreeMap<String, PathBuf>,
+    mapper: PathBuf,
+    order: HashMap<PathBuf, usize>,
+}
+
+impl Modules {
+    /// Scans units of the target for modules.
+    ///
+    /// Units providing imported modules are added to `files` along with units implementing
+    /// their headers. They're also added as dependencies of the importing units so that
+    /// importers recompile when the interface changes. Modules provided by linked libraries
+    /// (`env.linked_modules`) are imported from BMIs of the libraries instead. BMI files are
+    /// placed next to objects of interface units and `mapper` is written for compilers which
+    /// need it. Results of scanning are cached next to `mapper`.
+    pub fn resolve<F: Fn(&Language) -> GocarResult<Vec<OsString>>>(files: &mut HashMap<PathBuf, Vec<PathBuf>>, ignore_files: &HashSet<PathBuf>, obj_dir: &Path, mapper: PathBuf, env: &BuildEnv, options: F) -> GocarResult<Self> {
+        let obj_path = |unit: &Path| objs::get_obj_path(obj_dir, env.project_dir, unit_to_obj(unit).unwrap());
+        let mut cache_path = mapper.clone().into_os_string();
+        cache_path.push("-scan");
+        let mut cache = ScanCache::load(cache_path.into())?;
+        let mut scan = |unit: &Path, dependencies: &[PathBuf]| cache.get(unit, dependencies, || {
+            let language = env.project.languages.determine_from_file(unit).expect("Unknown extension");
+            scan_unit(unit, &obj_path(unit), language, &env.profile.compiler(language), &options(language)?)
+        });
+
+        let mut units = HashMap::new();
+        let mut providers = HashMap::new();
+        for unit in find_interface_units(env)? {
+            if !ignore_files.contains(&unit) && !files.contains_key(&unit) {
+                let modules = scan(&unit, &[])?;
+                add_unit(&mut units, &mut providers, unit, modules)?;
+            }
+        }
+
+        let mut scanned = HashSet::new();
+        loop {
+            let mut sorted = files.keys().filter(|unit| !scanned.contains(*unit)).cloned().collect::<Vec<_>>();
+            sorted.sort();
+            for unit in sorted {
+                if !units.contains_key(&unit) {
+                    let modules = scan(&unit, &files[&unit])?;
+                    add_unit(&mut units, &mut providers, unit.clone(), modules)?;
+                }
+                scanned.insert(unit);
+            }
+
+            let mut missing = Vec::new();
+            for unit in files.keys() {
+                for module in &units[unit].requires {
+                    match providers.get(module) {
+                        Some(provider) if !files.contains_key(provider) => missing.push(provider.clone()),
+                        Some(_) => (),
+                        None if env.linked_modules.contains_key(module) => (),
+                        None => return Err(ModuleError::UnknownModule { unit: unit.clone(), module: module.clone(), }.into()),
+                    }
+                }
+            }
+
+            if missing.is_empty() {
+                break;
+            }
+            missing.sort();
+            missing.dedup();
+
+            for (unit, headers) in scan_c_files(missing, ignore_files, env)? {
+                files.entry(unit).or_insert(headers);
+            }
+        }
+
+        cache.store(&units)?;
+
+        units.retain(|unit, _| files.contains_key(unit));
+        let order = build_order(&units, &providers)?;
+        for (unit, dependencies) in files.iter_mut() {
+            let mut required = HashSet::new();
+            required_units(unit, &units, &providers, &mut required);
+            // Importers of modules from libraries recompile when the BMI of the library changes
+            let linked = std::iter::once(unit)
+                .chain(required.iter().copied())
+                .flat_map(|unit| &units[unit].requires)
+                .filter(|module| !providers.contains_key(*module))
+                .map(|module| env.linked_modules[module].clone())
+                .collect::<Vec<_>>();
+            dependencies.extend(required.into_iter().cloned());
+            dependencies.extend(linked);
+        }
+
+        let bmis = units
+            .iter()
+            .flat_map(|(unit, modules)| modules.provides.iter().map(move |module| (module.clone(), obj_path(unit).with_extension("bmi"))))
+            .chain(env.linked_modules.iter().filter(|(module, _)| !providers.contains_key(*module)).map(|(module, bmi)| (module.clone(), bmi.clone())))
+            .collect::<BTreeMap<_, _>>();
+
+        let mapping = bmis.iter().map(|(module, bmi)| format!("{} {}\n", module, bmi.display())).collect::<String>();
+        create_dir_all(mapper.parent().unwrap())?;
+        write_if_changed(&mapper, mapping.as_bytes())?;
+
+        Ok(Modules {
+            units,
+            bmis,
+            mapper,
+            order,
+        })
+    }
+
+    /// Sorts units so that interface units compile before units importing them.
+    pub fn sort(&self, units: &mut [&Path]) {
+        units.sort_by_key(|unit| self.order.get(*unit).copied().unwrap_or(usize::MAX));
+    }
+
+    /// Checks whether the unit provides or imports any modules.
+    pub fn uses_modules(&self, unit: &Path) -> bool {
+        self.units.get(unit).map_or(false, |modules| !modules.provides.is_empty() || !modules.requires.is_empty())
+    }
+
+    /// Options needed to compile the unit
+    pub fn options(&self, unit: &Path, language: &Language) -> Vec<PathBuf> {
+        let modules = match self.units.get(unit) {
+            Some(modules) if !language.module_scan.is_empty() => modules,
+            _ => return Vec::new(),
+        };
+
+        let mut options = language.module_options
+            .iter()
+            .map(|option| option.replace("{mapper}", &self.mapper.to_string_lossy()).into())
+            .collect::<Vec<_>>();
+
+        if let Some(bmi) = modules.provides.first().map(|module| &self.bmis[module]) {
+            options.extend(language.module_interface_options.iter().map(|option| option.replace("{bmi}", &bmi.to_string_lossy()).into()));
+        }
+
+        for module in &modules.requires {
+            let bmi = self.bmis[module].to_string_lossy();
+            options.extend(language.module_import_options.iter().map(|option| option.replace("{name}", module).replace("{bmi}", &bmi).into()));
+        }
+
+        options
+    }
+}
+
+fn add_unit(units: &mut HashMap<PathBuf, UnitModules>, providers: &mut HashMap<String, PathBuf>, unit: PathBuf, modules: UnitModules) -> Result<(), ModuleError> {
+    for module in &modules.provides {
+        if let Some(other) = providers.insert(module.clone(), unit.clone()) {
+            return Err(ModuleError::DuplicateModule { module: module.clone(), units: [other, unit], });
+        }
+    }
+    units.insert(unit, modules);
+    Ok(())
+}
+
+/// Collects units providing modules imported by the unit directly or indirectly.
+///
+/// Modules without a provider come from linked libraries.
+fn required_units<'a>(unit: &Path, units: &'a HashMap<PathBuf, UnitModules>, providers: &'a HashMap<String, PathBuf>, required: &mut HashSet<&'a PathBuf>) {
+    for provider in units[unit].requires.iter().filter_map(|module| providers.get(module)) {
+        if required.insert(provider) {
+            required_units(provider, units, providers, required);
+        }
+    }
+}
+
+/// Orders units so that providers of modules go before their importers.
+fn build_order(units: &HashMap<PathBuf, UnitModules>, providers: &HashMap<String, PathBuf>) -> Result<HashMap<PathBuf, usize>, ModuleError> {
+    fn visit<'a>(unit: &'a Path, units: &'a HashMap<PathBuf, UnitModules>, providers: &'a HashMap<String, PathBuf>, visiting: &mut HashSet<&'a Path>, order: &mut HashMap<PathBuf, usize>) -> Result<(), ModuleError> {
+        if order.contains_key(unit) {
+            return Ok(());
+        }
+        if !visiting.insert(unit) {
+            return Err(ModuleError::Cycle { unit: unit.to_owned(), });
+        }
+
+        for provider in units[unit].requires.iter().filter_map(|module| providers.get(module)) {
+            if provider != unit {
+                visit(provider, units, providers, visiting, order)?;
+            }
+        }
+
+        visiting.remove(unit);
+        let position = order.len();
+        order.insert(unit.to_owned(), position);
+        Ok(())
+    }
+
+    let mut sorted = units.keys().collect::<Vec<_>>();
+    sorted.sort();
+
+    let mut order = HashMap::new();
+    for unit in sorted {
+        visit(unit, units, providers, &mut HashSet::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use super::{ModuleError, UnitModules, build_order};
+
+    #[test]
+    fn parse_p1689() {
+        let output = br#"{
+            "rules": [{
+                "primary-output": "main.o",
+                "provides": [{ "logical-name": "app", "is-interface": true }],
+                "requires": [{ "logical-name": "math" }, { "logical-name": "app:part" }]
+            }],
+            "version": 0,
+            "revision": 0
+        }"#;
+        let modules = UnitModules::parse(output).unwrap();
+        assert_eq!(modules.provides, ["app"]);
+        assert_eq!(modules.requires, ["math", "app:part"]);
+        assert_eq!(UnitModules::parse(br#"{"rules": [{"primary-output": "a.o"}]}"#).unwrap(), UnitModules::default());
+        assert!(UnitModules::parse(b"main.o: main.cpp").is_err());
+    }
+
+    fn unit(provides: &[&str], requires: &[&str]) -> UnitModules {
+        UnitModules {
+            provides: provides.iter().map(|module| (*module).to_owned()).collect(),
+            requires: requires.iter().map(|module| (*module).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn order() {
+        let mut units = HashMap::new();
+        units.insert(PathBuf::from("a.cpp"), unit(&[], &["math"]));
+        units.insert(PathBuf::from("math.cppm"), unit(&["math"], &["util"]));
+        units.insert(PathBuf::from("util.cppm"), unit(&["util"], &[]));
+        let mut providers = HashMap::new();
+        providers.insert("math".to_owned(), PathBuf::from("math.cppm"));
+        providers.insert("util".to_owned(), PathBuf::from("util.cppm"));
+
+        let order = build_order(&units, &providers).unwrap();
+        assert!(order[&PathBuf::from("util.cppm")] < order[&PathBuf::from("math.cppm")]);
+        assert!(order[&PathBuf::from("math.cppm")] < order[&PathBuf::from("a.cpp")]);
+
+        units.insert(PathBuf::from("util.cppm"), unit(&["util"], &["math"]));
+        match build_order(&units, &providers) {
+            Err(ModuleError::Cycle { .. }) => (),
+            other => panic!("unexpected result {:?}", other.map(drop)),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use super::{Command, GocarResult, HeaderExtractor, Language, ResultExt, create_dir_all, get_file_mtime, is_older, write_if_changed};
+
+/// Header precompiled for units of a single language
+pub struct PrecompiledHeader {
+    /// The original header
+    pub header: PathBuf,
+    /// Path passed to `-include`, the compiler finds the precompiled header next to it
+    pub include: PathBuf,
+    /// The precompiled header itself
+    pub output: PathBuf,
+}
+
+impl PrecompiledHeader {
+    /// Options injecting the header into a unit
+    pub fn options(&self) -> [PathBuf; 2] {
+        ["-include".into(), self.include.clone()]
+    }
+
+    /// Options injecting the original header, for preprocessing without the precompiled one
+    pub fn source_options(&self) -> [PathBuf; 2] {
+        ["-include".into(), self.header.clone()]
+    }
+}
+
+/// Precompiles the header into `out_dir` unless it's up to date.
+///
+/// The header is rebuilt if it or any header it includes changed or if `options` differ from
+/// the ones used previously, which are stored next to the precompiled header. A header
+/// forwarding to the original one is placed next to it too, because the compiler falls back
+/// to including the header itself if it can't use the precompiled one.
+pub fn build(header: &Path, language: &Language, compiler: &Path, options: Vec<OsString>, out_dir: &Path, header_extensions: &[String], strip_prefix: &Path) -> GocarResult<PrecompiledHeader> {
+    let name = header.file_name().expect("Precompiled header is not a file");
+    let include = out_dir.join(name);
+    let with_suffix = |suffix: &str| {
+        let mut path = include.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let output = with_suffix(".gch");
+    let depfile = with_suffix(".d");
+    let options_file = with_suffix(".options");
+
+    let header_type = language.header_language.as_deref().expect("Language doesn't support precompiled headers");
+    let options = options
+        .into_iter()
+        .chain(vec!["-x".into(), header_type.into()])
+        .collect::<Vec<_>>();
+    let options_content = options.iter().map(|option| format!("{}\n", option.to_string_lossy())).collect::<String>();
+
+    create_dir_all(out_dir)?;
+    write_if_changed(&include, format!("#include \"{}\"\n", header.display()).as_bytes())?;
+
+    if is_up_to_date(&output, &depfile, &options_file, &options_content, header_extensions)? {
+        return Ok(PrecompiledHeader { header: header.to_owned(), include, output, });
+    }
+
+    println!(" \u{1B}[32;1mPrecompiling\u{1B}[0m {:?}", output.strip_prefix(strip_prefix).unwrap_or(&output));
+    Command::new(compiler)
+        .args(options)
+        .arg("-MMD")
+        .arg("-MF")
+        .arg(&depfile)
+        .arg("-o")
+        .arg(&output)
+        .arg(header)
+        .spawn()?
+        .wait()?
+        .failure_into_error()?;
+    write_if_changed(&options_file, options_content.as_bytes())?;
+
+    Ok(PrecompiledHeader { header: header.to_owned(), include, output, })
+}
+
+fn is_up_to_date(output: &Path, depfile: &Path, options_file: &Path, options: &str, header_extensions: &[String]) -> GocarResult<bool> {
+    let mtime = match get_file_mtime(output)? {
+        Some(mtime) => mtime,
+        None => return Ok(false),
+    };
+
+    if std::fs::read(options_file).ok().as_deref() != Some(options.as_bytes()) {
+        return Ok(false);
+    }
+
+    let dependencies = match std::fs::File::open(depfile) {
+        Ok(file) => HeaderExtractor::new(io::BufReader::new(file), header_extensions)
+            .collect::<Result<Vec<_>, _>>()
+            .err_ctx(|| (depfile.to_owned(), "read file"))?,
+        Err(_) => return Ok(false),
+    };
+
+    Ok(!is_older(mtime, dependencies.iter())?)
+}
@@ -0,0 +1,463 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use super::{BuildEnv, FsResult, ResultExt};
+
+/// Format of the machine-readable test report
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TestFormat {
+    Junit,
+    Json,
+}
+
+impl std::str::FromStr for TestFormat {
+    type Err = UnknownTestFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "junit" => Ok(TestFormat::Junit),
+            "json" => Ok(TestFormat::Json),
+            _ => Err(UnknownTestFormat(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownTestFormat(String);
+
+impl fmt::Display for UnknownTestFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown test report format {}, expected junit or json", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestOptions {
+    /// Only tests containing this string in their name are run
+    pub filter: Option<String>,
+    pub jobs: usize,
+    /// Tests running longer than this are killed and reported as failed
+    pub timeout: Option<Duration>,
+    /// Stop starting new tests after the first failure
+    pub fail_fast: bool,
+    /// If set, the report is written into the target directory in this format
+    pub format: Option<TestFormat>,
+}
+
+impl Default for TestOptions {
+    fn default() -> Self {
+        TestOptions {
+            filter: None,
+            jobs: std::thread::available_parallelism().map_or(1, Into::into),
+            timeout: None,
+            fail_fast: true,
+            format: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TestOutcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+    BuildFailed(String),
+    /// Not run because of an earlier failure with fail-fast enabled
+    Skipped,
+}
+
+impl TestOutcome {
+    pub fn is_failure(&self) -> bool {
+        match self {
+            TestOutcome::Passed | TestOutcome::Skipped => false,
+            TestOutcome::Failed(_) | TestOutcome::TimedOut | TestOutcome::BuildFailed(_) => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+    pub duration: Duration,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|result| result.outcome.is_failure()).count()
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|result| matches!(result.outcome, TestOutcome::Passed)).count()
+    }
+
+    pub fn success(&self) -> bool {
+        self.failed() == 0
+    }
+}
+
+/// Unit test defined in more than one unit of a library
+#[derive(Debug)]
+pub struct DuplicateUnitTest {
+    pub library: PathBuf,
+    pub test: String,
+    pub units: Vec<String>,
+}
+
+impl fmt::Display for DuplicateUnitTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unit test {} of library {} is defined in multiple units: {}", self.test, self.library.display(), self.units.join(", "))
+    }
+}
+
+/// Parses `--list` output of a unit test runner, one `NAME<tab>UNIT` line per test, and returns
+/// sorted names of the tests.
+pub fn parse_unit_tests<'a>(library: &Path, list: &'a str) -> Result<Vec<&'a str>, DuplicateUnitTest> {
+    let mut tests = BTreeMap::<_, Vec<_>>::new();
+    for line in list.lines() {
+        let mut parts = line.splitn(2, '\t');
+        let name = parts.next().unwrap_or_default();
+        tests.entry(name).or_default().push(parts.next().unwrap_or_default());
+    }
+
+    match tests.iter().find(|(_, units)| units.len() > 1) {
+        Some((test, units)) => Err(DuplicateUnitTest {
+            library: library.to_owned(),
+            test: (*test).to_owned(),
+            units: units.iter().map(|unit| (*unit).to_owned()).collect(),
+        }),
+        None => Ok(tests.into_keys().collect()),
+    }
+}
+
+/// Whether any of the units defines unit tests using `GOCAR_TEST(name)`
+pub fn has_unit_tests<'a, I: IntoIterator<Item=&'a PathBuf>>(units: I) -> FsResult<bool> {
+    const MACRO: &[u8] = b"GOCAR_TEST";
+
+    for unit in units {
+        let content = std::fs::read(unit).err_ctx(|| (unit.clone(), "read file"))?;
+        let uses_macro = content
+            .windows(MACRO.len())
+            .enumerate()
+            .filter(|(_, window)| *window == MACRO)
+            .any(|(position, _)| content[(position + MACRO.len())..].iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'('));
+        if uses_macro {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// A test program which was built successfully
+pub struct TestBinary {
+    pub name: String,
+    pub path: PathBuf,
+    /// Arguments selecting the test if the binary contains several of them
+    pub args: Vec<OsString>,
+}
+
+/// Returns programs in `dir` matching the filter, sorted by name.
+///
+/// Every `.c` and `.cpp` file is a separate program (a test, a benchmark or an example), named
+/// after the file stem.
+pub fn discover_programs(dir: &Path, filter: Option<&str>) -> FsResult<Vec<(String, PathBuf)>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).err_ctx(|| (dir.to_owned(), "read directory")),
+    };
+
+    let mut tests = Vec::new();
+    for entry in entries {
+        let path = entry.err_ctx(|| (dir.to_owned(), "read directory"))?.path();
+        let extension_is_valid = path.extension().map_or(false, |extension| extension == "c" || extension == "cpp");
+        if !extension_is_valid {
+            continue;
+        }
+
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        if filter.map_or(true, |filter| name.contains(filter)) {
+            tests.push((name, path));
+        }
+    }
+    tests.sort();
+
+    Ok(tests)
+}
+
+fn read_to_end_in_thread<R: Read + Send + 'static>(reader: Option<R>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut reader) = reader {
+            // Errors are ignored, the output is only informative.
+            let _ = reader.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+fn run_test(test: &TestBinary, project_dir: &Path, timeout: Option<Duration>) -> TestResult {
+    let start = Instant::now();
+    let child = std::process::Command::new(&test.path)
+        .args(&test.args)
+        .current_dir(project_dir)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(error) => return TestResult {
+            name: test.name.clone(),
+            outcome: TestOutcome::Failed(format!("failed to run {}: {}", test.path.display(), error)),
+            duration: start.elapsed(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        },
+    };
+
+    let stdout = read_to_end_in_thread(child.stdout.take());
+    let stderr = read_to_end_in_thread(child.stderr.take());
+
+    let outcome = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break TestOutcome::Passed,
+            Ok(Some(status)) => match status.code() {
+                Some(code) => break TestOutcome::Failed(format!("exit code {}", code)),
+                None => break TestOutcome::Failed("killed by a signal".to_owned()),
+            },
+            Ok(None) if timeout.map_or(false, |timeout| start.elapsed() > timeout) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break TestOutcome::TimedOut;
+            },
+            Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+            Err(error) => break TestOutcome::Failed(format!("failed to wait for the test: {}", error)),
+        }
+    };
+
+    TestResult {
+        name: test.name.clone(),
+        outcome,
+        duration: start.elapsed(),
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }
+}
+
+fn print_result(result: &TestResult) {
+    let status = match result.outcome {
+        TestOutcome::Passed => "\u{1B}[32mok\u{1B}[0m",
+        TestOutcome::Failed(_) | TestOutcome::BuildFailed(_) => "\u{1B}[31mFAILED\u{1B}[0m",
+        TestOutcome::TimedOut => "\u{1B}[31mTIMEOUT\u{1B}[0m",
+        TestOutcome::Skipped => "\u{1B}[33mskipped\u{1B}[0m",
+    };
+    println!("test {} ... {}", result.name, status);
+}
+
+/// Runs built tests in parallel.
+///
+/// `build_failures` are included in the report as failed tests.
+pub fn run_tests(tests: Vec<TestBinary>, build_failures: Vec<TestResult>, options: &TestOptions, env: &BuildEnv) -> FsResult<TestReport> {
+    for failure in &build_failures {
+        print_result(failure);
+    }
+
+    let stop = AtomicBool::new(options.fail_fast && !build_failures.is_empty());
+    let queue = Mutex::new(tests.iter());
+    let results = Mutex::new(Vec::with_capacity(tests.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.max(1) {
+            scope.spawn(|| loop {
+                let test = match queue.lock().unwrap().next() {
+                    Some(test) => test,
+                    None => break,
+                };
+
+                let result = if stop.load(Ordering::SeqCst) {
+                    TestResult { name: test.name.clone(), outcome: TestOutcome::Skipped, duration: Duration::default(), stdout: Vec::new(), stderr: Vec::new(), }
+                } else {
+                    let result = run_test(test, env.project_dir, options.timeout);
+                    if result.outcome.is_failure() && options.fail_fast {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    result
+                };
+
+                print_result(&result);
+                results.lock().unwrap().push(result);
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.extend(build_failures);
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+    let report = TestReport { results, };
+
+    print_failures(&report);
+    println!("test result: {}. total: {}; passed: {}; failed: {}", if report.success() { "\u{1B}[32mok\u{1B}[0m" } else { "\u{1B}[31mFAILED\u{1B}[0m" }, report.results.len(), report.passed(), report.failed());
+
+    if let Some(format) = options.format {
+        let (file_name, content) = match format {
+            TestFormat::Junit => ("test-results.xml", junit_report(&report)),
+            TestFormat::Json => ("test-results.json", json_report(&report)),
+        };
+        let path = env.target_dir.join(file_name);
+        std::fs::write(&path, content).err_ctx(|| (path.clone(), "write file"))?;
+        println!("test report written to {}", path.strip_prefix(env.strip_prefix).unwrap_or(&path).display());
+    }
+
+    Ok(report)
+}
+
+fn print_failures(report: &TestReport) {
+    let failures = report.results.iter().filter(|result| result.outcome.is_failure()).collect::<Vec<_>>();
+    if failures.is_empty() {
+        return;
+    }
+
+    println!("\nfailures:\n");
+    for failure in &failures {
+        match &failure.outcome {
+            TestOutcome::Failed(reason) | TestOutcome::BuildFailed(reason) => println!("---- {}: {} ----", failure.name, reason),
+            TestOutcome::TimedOut => println!("---- {}: timed out after {:.1}s ----", failure.name, failure.duration.as_secs_f64()),
+            TestOutcome::Passed | TestOutcome::Skipped => (),
+        }
+        if !failure.stdout.is_empty() {
+            println!("stdout:\n{}", String::from_utf8_lossy(&failure.stdout));
+        }
+        if !failure.stderr.is_empty() {
+            println!("stderr:\n{}", String::from_utf8_lossy(&failure.stderr));
+        }
+    }
+
+    println!("failed tests:");
+    for failure in &failures {
+        println!("    {}", failure.name);
+    }
+    println!();
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            c if c.is_control() && c != '\n' && c != '\t' => (),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn escape_json(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn junit_report(report: &TestReport) -> String {
+    let total_time = report.results.iter().map(|result| result.duration.as_secs_f64()).sum::<f64>();
+    let skipped = report.results.iter().filter(|result| matches!(result.outcome, TestOutcome::Skipped)).count();
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n  <testsuite name=\"gocar\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n", report.results.len(), report.failed(), skipped, total_time);
+    for result in &report.results {
+        xml.push_str(&format!("    <testcase name=\"{}\" classname=\"tests\" time=\"{:.3}\">\n", escape_xml(&result.name), result.duration.as_secs_f64()));
+        match &result.outcome {
+            TestOutcome::Passed => (),
+            TestOutcome::Failed(reason) => xml.push_str(&format!("      <failure message=\"{}\"/>\n", escape_xml(reason))),
+            TestOutcome::BuildFailed(reason) => xml.push_str(&format!("      <error message=\"{}\"/>\n", escape_xml(reason))),
+            TestOutcome::TimedOut => xml.push_str("      <failure message=\"timed out\"/>\n"),
+            TestOutcome::Skipped => xml.push_str("      <skipped/>\n"),
+        }
+        if !result.stdout.is_empty() {
+            xml.push_str(&format!("      <system-out>{}</system-out>\n", escape_xml(&String::from_utf8_lossy(&result.stdout))));
+        }
+        if !result.stderr.is_empty() {
+            xml.push_str(&format!("      <system-err>{}</system-err>\n", escape_xml(&String::from_utf8_lossy(&result.stderr))));
+        }
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+fn json_report(report: &TestReport) -> String {
+    let tests = report.results.iter().map(|result| {
+        let (outcome, message) = match &result.outcome {
+            TestOutcome::Passed => ("passed", None),
+            TestOutcome::Failed(reason) => ("failed", Some(reason.as_str())),
+            TestOutcome::BuildFailed(reason) => ("build_failed", Some(reason.as_str())),
+            TestOutcome::TimedOut => ("timed_out", None),
+            TestOutcome::Skipped => ("skipped", None),
+        };
+        let message = message.map_or_else(|| "null".to_owned(), |message| format!("\"{}\"", escape_json(message)));
+        format!("    {{\"name\": \"{}\", \"outcome\": \"{}\", \"message\": {}, \"duration\": {:.3}, \"stdout\": \"{}\", \"stderr\": \"{}\"}}", escape_json(&result.name), outcome, message, result.duration.as_secs_f64(), escape_json(&String::from_utf8_lossy(&result.stdout)), escape_json(&String::from_utf8_lossy(&result.stderr)))
+    }).collect::<Vec<_>>();
+
+    format!("{{\n  \"total\": {},\n  \"passed\": {},\n  \"failed\": {},\n  \"tests\": [\n{}\n  ]\n}}\n", report.results.len(), report.passed(), report.failed(), tests.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+    use super::{TestOutcome, TestReport, TestResult, escape_json, junit_report, parse_unit_tests};
+
+    fn result(name: &str, outcome: TestOutcome) -> TestResult {
+        TestResult { name: name.to_owned(), outcome, duration: Duration::from_millis(1500), stdout: b"out <1>".to_vec(), stderr: Vec::new(), }
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(escape_json("a\"b\\c\nd\u{1}"), "a\\\"b\\\\c\\nd\\u0001");
+    }
+
+    #[test]
+    fn junit() {
+        let report = TestReport { results: vec![result("a", TestOutcome::Passed), result("b", TestOutcome::Failed("exit code 1".to_owned()))], };
+        let xml = junit_report(&report);
+        assert!(xml.contains("tests=\"2\" failures=\"1\" skipped=\"0\" time=\"3.000\""));
+        assert!(xml.contains("<testcase name=\"b\" classname=\"tests\" time=\"1.500\">\n      <failure message=\"exit code 1\"/>"));
+        assert!(xml.contains("<system-out>out &lt;1&gt;</system-out>"));
+    }
+
+    #[test]
+    fn unit_test_list() {
+        let library = Path::new("math");
+        assert_eq!(parse_unit_tests(library, "subtracts\tsrc/sub.c\nadds\tsrc/add.c\n").unwrap(), ["adds", "subtracts"]);
+        assert!(parse_unit_tests(library, "").unwrap().is_empty());
+
+        let error = parse_unit_tests(library, "adds\tsrc/add.c\nadds\tsrc/sum.c\n").unwrap_err();
+        assert_eq!(error.to_string(), "unit test adds of library math is defined in multiple units: src/add.c, src/sum.c");
+    }
+}
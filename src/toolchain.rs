@@ -0,0 +1,382 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use super::{Command, FsResult, GocarResult, Version, create_dir_all, write_if_changed};
+
+/// Kind of compiler, which determines options understood by it
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum CompilerFamily {
+    Gcc,
+    Clang,
+    Tcc,
+}
+
+impl CompilerFamily {
+    /// Determines the family from output of `compiler --version`
+    fn from_version(version: &str) -> Option<Self> {
+        if version.contains("clang") {
+            Some(CompilerFamily::Clang)
+        } else if version.starts_with("tcc") {
+            Some(CompilerFamily::Tcc)
+        } else if version.contains("Free Software Foundation") || version.starts_with("gcc") || version.starts_with("g++") {
+            Some(CompilerFamily::Gcc)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            CompilerFamily::Gcc => "gcc",
+            CompilerFamily::Clang => "clang",
+            CompilerFamily::Tcc => "tcc",
+        }
+    }
+
+    /// Whether the compiler understands `-x c-header` and finds `.gch` files of `-include`d headers
+    pub fn supports_pch(&self) -> bool {
+        *self != CompilerFamily::Tcc
+    }
+
+    /// Translates options of languages scanning units for headers from the GCC dialect.
+    ///
+    /// tcc doesn't support `-MM`, so it writes the depfile to stdout instead, which lists system
+    /// headers as well.
+    pub fn scan_options<'a>(&self, options: &'a [String]) -> Cow<'a, [String]> {
+        match self {
+            CompilerFamily::Tcc if options == ["-MM"] => ["-E", "-MD", "-MF", "/dev/stdout", "-o", "/dev/null"].iter().map(|option| (*option).to_owned()).collect(),
+            _ => Cow::Borrowed(options),
+        }
+    }
+}
+
+impl fmt::Display for CompilerFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for CompilerFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gcc" => Ok(CompilerFamily::Gcc),
+            "clang" => Ok(CompilerFamily::Clang),
+            "tcc" => Ok(CompilerFamily::Tcc),
+            _ => Err(format!("unknown compiler family {}, expected gcc, clang or tcc", s)),
+        }
+    }
+}
+
+// Deserialized from a string since families are used as keys of tables
+impl<'de> serde::Deserialize<'de> for CompilerFamily {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let family = String::deserialize(deserializer)?;
+        family.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Detected compiler
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CompilerInfo {
+    pub family: CompilerFamily,
+    pub version: Version,
+}
+
+impl CompilerInfo {
+    /// Determines the compiler from its predefined macros, as printed by `-dM -E`
+    ///
+    /// clang defines GCC macros too, so it's checked first.
+    fn from_macros(macros: &str) -> Option<Self> {
+        let macros = macros
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.strip_prefix("#define ")?.splitn(2, ' ');
+                Some((parts.next()?, parts.next().unwrap_or("")))
+            })
+            .collect::<HashMap<_, _>>();
+        let number = |name: &str| macros.get(name).and_then(|value| value.parse::<u64>().ok());
+        let version = |major, minor, patch| Some(Version { major: number(major)?, minor: number(minor).unwrap_or(0), patch: number(patch).unwrap_or(0), });
+
+        if macros.contains_key("__clang__") {
+            Some(CompilerInfo { family: CompilerFamily::Clang, version: version("__clang_major__", "__clang_minor__", "__clang_patchlevel__")?, })
+        } else if let Some(tinyc) = number("__TINYC__") {
+            Some(CompilerInfo { family: CompilerFamily::Tcc, version: Version { major: tinyc / 10000, minor: tinyc / 100 % 100, patch: tinyc % 100, }, })
+        } else if macros.contains_key("__GNUC__") {
+            Some(CompilerInfo { family: CompilerFamily::Gcc, version: version("__GNUC__", "__GNUC_MINOR__", "__GNUC_PATCHLEVEL__")?, })
+        } else {
+            None
+        }
+    }
+
+    /// Determines the compiler from output of `compiler --version`
+    fn from_version(version: &str) -> Option<Self> {
+        let family = CompilerFamily::from_version(version)?;
+        let version = version
+            .lines()
+            .next()?
+            .split_whitespace()
+            .filter(|word| word.contains('.'))
+            .find_map(|word| word.parse().ok())?;
+
+        Some(CompilerInfo { family, version, })
+    }
+
+    /// Defines identifying the compiler to the code, e.g. `-DGOCAR_CC_CLANG` and
+    /// `-DGOCAR_CC_VERSION=150003` for clang 15.0.3
+    pub fn defines(&self) -> [PathBuf; 2] {
+        let version = self.version.major * 10000 + self.version.minor * 100 + self.version.patch;
+        [
+            format!("-DGOCAR_CC_{}", self.family.name().to_uppercase()).into(),
+            format!("-DGOCAR_CC_VERSION={}", version).into(),
+        ]
+    }
+}
+
+/// Link-time optimization mode of a profile
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lto {
+    #[default]
+    Off,
+    Thin,
+    Full,
+}
+
+impl fmt::Display for Lto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lto::Off => write!(f, "off"),
+            Lto::Thin => write!(f, "thin"),
+            Lto::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// The compiler doesn't support requested LTO mode
+#[derive(Debug)]
+pub struct UnsupportedLto {
+    pub compiler: PathBuf,
+    pub lto: Lto,
+}
+
+impl fmt::Display for UnsupportedLto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compiler {} doesn't support {} LTO", self.compiler.display(), self.lto)
+    }
+}
+
+/// The compiler is neither GCC, clang nor tcc
+#[derive(Debug)]
+pub struct UnsupportedCompiler {
+    pub compiler: PathBuf,
+    /// First line of `--version` output
+    pub version: String,
+}
+
+impl fmt::Display for UnsupportedCompiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported compiler {} ({}), only GCC, clang and tcc are supported", self.compiler.display(), self.version)
+    }
+}
+
+/// Compilers used during a build
+///
+/// Compilers are only detected when needed and the results are cached in a file in the target
+/// directory of the profile. The cache is invalidated when the compiler executable changes.
+pub struct Toolchain {
+    cache_file: PathBuf,
+    compilers: Mutex<HashMap<PathBuf, (Option<u128>, CompilerInfo)>>,
+}
+
+impl Toolchain {
+    /// Loads previously detected compilers from `cache_file`, ignoring invalid entries.
+    pub fn load(cache_file: PathBuf) -> Self {
+        let compilers = std::fs::read_to_string(&cache_file)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(parse_cache_line)
+            .map(|(compiler, (mtime, info))| (compiler, (Some(mtime), info)))
+            .collect();
+
+        Toolchain {
+            cache_file,
+            compilers: Mutex::new(compilers),
+        }
+    }
+
+    /// Stores detected compilers in the cache file.
+    pub fn save(&self) -> FsResult<()> {
+        let compilers = self.compilers.lock().unwrap();
+        // Compilers without a known executable are detected again by every build
+        let mut lines = compilers
+            .iter()
+            .filter_map(|(compiler, (mtime, info))| Some(format!("{} {} {} {}\n", (*mtime)?, info.family, info.version, compiler.display())))
+            .collect::<Vec<_>>();
+        lines.sort();
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        create_dir_all(self.cache_file.parent().unwrap())?;
+        write_if_changed(&self.cache_file, lines.concat().as_bytes())
+    }
+
+    /// Detects the compiler using predefined macros, falling back to `--version`.
+    ///
+    /// Fails if the compiler can't be run or isn't supported.
+    pub fn detect(&self, compiler: &Path) -> GocarResult<CompilerInfo> {
+        let mtime = executable_mtime(compiler);
+        if let Some((cached_mtime, info)) = self.compilers.lock().unwrap().get(compiler) {
+            if *cached_mtime == mtime {
+                return Ok(*info);
+            }
+        }
+
+        let macros = Command::new(compiler)
+            .args(["-dM", "-E", "-x", "c", "/dev/null"])
+            .output()
+            .ok()
+            .and_then(|macros| CompilerInfo::from_macros(&String::from_utf8_lossy(&macros)));
+        let info = match macros {
+            Some(info) => info,
+            None => {
+                let version = Command::new(compiler).arg("--version").output()?;
+                let version = String::from_utf8_lossy(&version);
+                CompilerInfo::from_version(&version).ok_or_else(|| UnsupportedCompiler {
+                    compiler: compiler.to_owned(),
+                    version: version.lines().next().unwrap_or_default().to_owned(),
+                })?
+            },
+        };
+
+        println!("    \u{1B}[32;1mDetected\u{1B}[0m {} ({} {})", compiler.display(), info.family, info.version);
+        self.compilers.lock().unwrap().insert(compiler.to_owned(), (mtime, info));
+        Ok(info)
+    }
+
+    /// Options enabling LTO, both when compiling and linking
+    pub fn lto_options(&self, compiler: &Path, lto: Lto) -> GocarResult<Vec<PathBuf>> {
+        if lto == Lto::Off {
+            return Ok(Vec::new());
+        }
+
+        let options: &[&str] = match (lto, self.detect(compiler)?.family) {
+            (Lto::Full, CompilerFamily::Gcc) => &["-flto"],
+            (Lto::Full, CompilerFamily::Clang) => &["-flto=full"],
+            (Lto::Thin, CompilerFamily::Clang) => &["-flto=thin"],
+            _ => return Err(UnsupportedLto { compiler: compiler.to_owned(), lto, }.into()),
+        };
+
+        Ok(options.iter().map(Into::into).collect())
+    }
+
+    /// Archiver creating static libraries from objects produced by the compiler
+    ///
+    /// Plain `ar` can't index LTO objects, so the wrapper of the compiler (`gcc-ar` or
+    /// `llvm-ar`) is used instead, keeping the target prefix and the version suffix of the
+    /// compiler, e.g. `arm-none-eabi-gcc-ar` or `llvm-ar-15`.
+    pub fn archiver(&self, compiler: &Path, lto: Lto) -> GocarResult<PathBuf> {
+        if lto == Lto::Off {
+            return Ok("ar".into());
+        }
+
+        let name = compiler.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let archiver = match self.detect(compiler)?.family {
+            CompilerFamily::Gcc => wrapper_name(&name, &["g++", "gcc", "c++", "cc"], "gcc-ar", true),
+            CompilerFamily::Clang => wrapper_name(&name, &["clang++", "clang"], "llvm-ar", false),
+            CompilerFamily::Tcc => return Ok("ar".into()),
+        };
+        Ok(compiler.with_file_name(archiver))
+    }
+}
+
+/// Modification time of the compiler executable, looked up in `PATH` if needed
+fn executable_mtime(compiler: &Path) -> Option<u128> {
+    let executable = if compiler.components().count() > 1 {
+        compiler.to_owned()
+    } else {
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|dir| dir.join(compiler))
+            .find(|path| path.is_file())?
+    };
+
+    let mtime = std::fs::metadata(executable).ok()?.modified().ok()?;
+    Some(mtime.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+/// Parses `MTIME FAMILY VERSION COMPILER` line of the cache file
+fn parse_cache_line(line: &str) -> Option<(PathBuf, (u128, CompilerInfo))> {
+    let mut parts = line.splitn(4, ' ');
+    let mtime = parts.next()?.parse().ok()?;
+    let family = parts.next()?.parse().ok()?;
+    let version = parts.next()?.parse().ok()?;
+    let compiler = parts.next()?.into();
+
+    Some((compiler, (mtime, CompilerInfo { family, version, })))
+}
+
+/// Replaces the name of the compiler driver in `name` by `wrapper`
+fn wrapper_name(name: &str, drivers: &[&str], wrapper: &str, keep_prefix: bool) -> String {
+    for driver in drivers {
+        if let Some(position) = name.rfind(driver) {
+            let prefix = if keep_prefix { &name[..position] } else { "" };
+            return format!("{}{}{}", prefix, wrapper, &name[(position + driver.len())..]);
+        }
+    }
+
+    wrapper.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{CompilerFamily, CompilerInfo, Version, parse_cache_line, wrapper_name};
+
+    fn info(family: CompilerFamily, major: u64, minor: u64, patch: u64) -> CompilerInfo {
+        CompilerInfo { family, version: Version { major, minor, patch, }, }
+    }
+
+    #[test]
+    fn from_version() {
+        assert_eq!(CompilerInfo::from_version("gcc (Debian 12.2.0-14) 12.2.0\nCopyright (C) 2022 Free Software Foundation, Inc."), Some(info(CompilerFamily::Gcc, 12, 2, 0)));
+        assert_eq!(CompilerInfo::from_version("cc (GCC) 13.1.1 20230429\nCopyright (C) 2023 Free Software Foundation, Inc."), Some(info(CompilerFamily::Gcc, 13, 1, 1)));
+        assert_eq!(CompilerInfo::from_version("Debian clang version 14.0.6\nTarget: x86_64-pc-linux-gnu"), Some(info(CompilerFamily::Clang, 14, 0, 6)));
+        assert_eq!(CompilerInfo::from_version("Apple clang version 15.0.0 (clang-1500.0.40.1)"), Some(info(CompilerFamily::Clang, 15, 0, 0)));
+        assert_eq!(CompilerInfo::from_version("tcc version 0.9.27 (x86_64 Linux)"), Some(info(CompilerFamily::Tcc, 0, 9, 27)));
+        assert_eq!(CompilerInfo::from_version("Microsoft (R) C/C++ Optimizing Compiler Version 19.37"), None);
+    }
+
+    #[test]
+    fn from_macros() {
+        let gcc = "#define __STDC__ 1\n#define __GNUC__ 12\n#define __GNUC_MINOR__ 2\n#define __GNUC_PATCHLEVEL__ 0\n";
+        assert_eq!(CompilerInfo::from_macros(gcc), Some(info(CompilerFamily::Gcc, 12, 2, 0)));
+        let clang = "#define __GNUC__ 4\n#define __clang__ 1\n#define __clang_major__ 15\n#define __clang_minor__ 0\n#define __clang_patchlevel__ 3\n";
+        assert_eq!(CompilerInfo::from_macros(clang), Some(info(CompilerFamily::Clang, 15, 0, 3)));
+        assert_eq!(CompilerInfo::from_macros("#define __TINYC__ 927\n#define __GNUC__ 2\n"), Some(info(CompilerFamily::Tcc, 0, 9, 27)));
+        assert_eq!(CompilerInfo::from_macros("#define __STDC__ 1\n"), None);
+        assert_eq!(info(CompilerFamily::Clang, 15, 0, 3).defines(), [Path::new("-DGOCAR_CC_CLANG"), Path::new("-DGOCAR_CC_VERSION=150003")]);
+    }
+
+    #[test]
+    fn cache_line() {
+        let (compiler, (mtime, detected)) = parse_cache_line("1700000000 gcc 12.2.0 /opt/my compilers/gcc").unwrap();
+        assert_eq!(compiler, Path::new("/opt/my compilers/gcc"));
+        assert_eq!(mtime, 1700000000);
+        assert_eq!(detected, info(CompilerFamily::Gcc, 12, 2, 0));
+        assert!(parse_cache_line("1700000000 msvc 19.37.0 cl").is_none());
+    }
+
+    #[test]
+    fn archivers() {
+        assert_eq!(wrapper_name("gcc", &["g++", "gcc", "c++", "cc"], "gcc-ar", true), "gcc-ar");
+        assert_eq!(wrapper_name("cc", &["g++", "gcc", "c++", "cc"], "gcc-ar", true), "gcc-ar");
+        assert_eq!(wrapper_name("arm-none-eabi-g++", &["g++", "gcc", "c++", "cc"], "gcc-ar", true), "arm-none-eabi-gcc-ar");
+        assert_eq!(wrapper_name("gcc-12", &["g++", "gcc", "c++", "cc"], "gcc-ar", true), "gcc-ar-12");
+        assert_eq!(wrapper_name("clang++-15", &["clang++", "clang"], "llvm-ar", false), "llvm-ar-15");
+    }
+}
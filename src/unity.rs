@@ -0,0 +1,86 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use super::{FsResult, Languages, canonicalize, create_dir_all, write_if_changed};
+
+/// Replaces units with generated unity files each including a batch of them.
+///
+/// Units are batched per language, so C and C++ never end up in the same file. Units for which
+/// `exclude` returns true and units of languages which don't support unity builds are kept
+/// as they are. Generated files depend on all included units and their dependencies.
+pub fn combine<F: Fn(&Path) -> bool>(files: HashMap<PathBuf, Vec<PathBuf>>, languages: &Languages, batch_size: Option<usize>, out_dir: &Path, exclude: F) -> FsResult<HashMap<PathBuf, Vec<PathBuf>>> {
+    let mut result = HashMap::new();
+    let mut by_language = BTreeMap::new();
+    for (unit, dependencies) in files {
+        match languages.determine_from_file(&unit) {
+            Some(language) if language.unity && !exclude(&unit) => by_language.entry(&language.name).or_insert_with(|| (language, Vec::new())).1.push((unit, dependencies)),
+            _ => {
+                result.insert(unit, dependencies);
+            },
+        }
+    }
+
+    if by_language.is_empty() {
+        return Ok(result);
+    }
+
+    // Objects of units are placed according to their path relative to the project
+    create_dir_all(out_dir)?;
+    let out_dir = canonicalize(out_dir)?;
+    for (name, (language, mut units)) in by_language {
+        units.sort();
+        let extension = &language.extensions[0];
+        let batch_size = batch_size.unwrap_or(units.len()).max(1);
+        for (i, batch) in units.chunks(batch_size).enumerate() {
+            let path = out_dir.join(format!("{}_{}.{}", name, i, extension));
+            write_if_changed(&path, unity_source(batch.iter().map(|(unit, _)| &**unit)).as_bytes())?;
+
+            let dependencies = batch
+                .iter()
+                .flat_map(|(unit, dependencies)| std::iter::once(unit).chain(dependencies))
+                .cloned()
+                .collect();
+            result.insert(path, dependencies);
+        }
+    }
+
+    Ok(result)
+}
+
+fn unity_source<'a, I: IntoIterator<Item=&'a Path>>(units: I) -> String {
+    units
+        .into_iter()
+        .map(|unit| format!("#include \"{}\"\n", unit.display()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use super::{Languages, combine, unity_source};
+
+    #[test]
+    fn source() {
+        assert_eq!(unity_source(vec![Path::new("/p/a.c"), Path::new("/p/b.c")]), "#include \"/p/a.c\"\n#include \"/p/b.c\"\n");
+    }
+
+    #[test]
+    fn batches() {
+        let out_dir = std::env::temp_dir().join(format!("gocar-unity-{}", std::process::id()));
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let out_dir = out_dir.canonicalize().unwrap();
+        let mut files = HashMap::new();
+        for unit in &["/p/a.c", "/p/b.c", "/p/c.c", "/p/d.cpp", "/p/e.cpp", "/p/f.S"] {
+            files.insert(PathBuf::from(unit), vec![PathBuf::from("/p/common.h")]);
+        }
+
+        let combined = combine(files, &Languages::default(), Some(2), &out_dir, |unit| unit == Path::new("/p/e.cpp")).unwrap();
+        let mut units = combined.keys().map(|unit| unit.strip_prefix(&out_dir).unwrap_or(unit).to_owned()).collect::<Vec<_>>();
+        units.sort();
+        assert_eq!(units, [Path::new("/p/e.cpp"), Path::new("/p/f.S"), Path::new("c_0.c"), Path::new("c_1.c"), Path::new("cpp_0.cpp")]);
+        assert_eq!(combined[&out_dir.join("c_0.c")], [Path::new("/p/a.c"), Path::new("/p/common.h"), Path::new("/p/b.c"), Path::new("/p/common.h")]);
+        assert_eq!(std::fs::read_to_string(out_dir.join("c_1.c")).unwrap(), "#include \"/p/c.c\"\n");
+
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+}